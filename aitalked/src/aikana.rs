@@ -0,0 +1,671 @@
+//! `text_to_kana` が返す AIKana (読み・韻律記述) の構文木
+//!
+//! ```text
+//! <S>(Irq MARK=_AI@0)コ^ンニチワ(Irq MARK=_AI@5)、(Pau MSEC=200)セ^カイ<F>
+//! ```
+//!
+//! - `<...>`: 文の開始・終了などのマーカー
+//! - `(Name KEY=VALUE ...)`: ブックマーク・ポーズ・音量/話速/高さなどのコマンド
+//! - `'` `^`: アクセント記号
+//! - `|` `/` `,` `、` `.` `。` `?` `？` `!` `！`: アクセント句の区切り
+//!
+//! 解釈できない文字も `Node::Other` として保持し、コマンドは元の表記 (`Spanned::raw`) を残すことで、
+//! `to_string()` で元の文字列に戻せるようにしている
+
+use std::ffi::CString;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use encoding_rs::SHIFT_JIS;
+
+mod edit;
+pub mod lint;
+
+pub use edit::EditError;
+
+pub const AUTO_BOOKMARK_PREFIX: &str = "_AI@";
+
+/// AIKana の読みに使える文字 (カタカナのみ。ひらがなは `Node::Other` になり、`lint` ではエラー)
+pub fn is_kana(c: char) -> bool {
+    matches!(c, 'ァ'..='ヺ' | 'ー' | 'ヽ' | 'ヾ')
+}
+
+/// 拗音などの小書き文字 (直前の文字と合わせて1モーラ)
+pub fn is_small_kana(c: char) -> bool {
+    "ァィゥェォャュョヮ".contains(c)
+}
+
+pub fn is_accent(c: char) -> bool {
+    matches!(c, '\'' | '^')
+}
+
+pub fn is_symbol(c: char) -> bool {
+    matches!(c, '_' | '%' | '~' | '$' | '0'..='9')
+}
+
+/// カナ文字列のモーラ数
+pub fn count_morae(kana: &str) -> usize {
+    kana.chars().filter(|c| !is_small_kana(*c)).count()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boundary {
+    /// `|`
+    Phrase,
+    /// `/`
+    Slash,
+    /// `,` `、`
+    Comma(char),
+    /// `.` `。`
+    Period(char),
+    /// `?` `？`
+    Question(char),
+    /// `!` `！`
+    Exclamation(char),
+}
+
+impl Boundary {
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            '|' => Some(Self::Phrase),
+            '/' => Some(Self::Slash),
+            ',' | '、' => Some(Self::Comma(c)),
+            '.' | '。' => Some(Self::Period(c)),
+            '?' | '？' => Some(Self::Question(c)),
+            '!' | '！' => Some(Self::Exclamation(c)),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(&self) -> char {
+        match *self {
+            Self::Phrase => '|',
+            Self::Slash => '/',
+            Self::Comma(c) | Self::Period(c) | Self::Question(c) | Self::Exclamation(c) => c,
+        }
+    }
+
+    /// 文の終わりを表すか
+    pub fn is_sentence_end(&self) -> bool {
+        matches!(
+            self,
+            Self::Period(_) | Self::Question(_) | Self::Exclamation(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProsodyKind {
+    /// `(Vol ABSLEVEL=...)`
+    Volume,
+    /// `(Spd ABSSPEED=...)`
+    Speed,
+    /// `(Pit ABSLEVEL=...)`
+    Pitch,
+    /// `(EMPH ABSLEVEL=...)`
+    Emphasis,
+}
+
+impl ProsodyKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Volume => "Vol",
+            Self::Speed => "Spd",
+            Self::Pitch => "Pit",
+            Self::Emphasis => "EMPH",
+        }
+    }
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            Self::Speed => "ABSSPEED",
+            _ => "ABSLEVEL",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Vol" => Some(Self::Volume),
+            "Spd" => Some(Self::Speed),
+            "Pit" => Some(Self::Pitch),
+            "EMPH" => Some(Self::Emphasis),
+            _ => None,
+        }
+    }
+}
+
+/// 書き戻し時に小数点以下の桁数を保つための数値
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    pub value: f32,
+    pub precision: usize,
+}
+
+impl Level {
+    pub fn new(value: f32) -> Self {
+        Self {
+            value,
+            precision: 2,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = std::num::ParseFloatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            value: s.parse()?,
+            precision: s.split_once('.').map(|(_, f)| f.len()).unwrap_or(0),
+        })
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.*}", self.precision, self.value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// `(Irq MARK=name)`
+    Bookmark(String),
+    /// `(Irq MARK=_AI@n)`: 入力テキスト上の位置
+    AutoBookmark(u32),
+    /// `(Pau MSEC=n)`
+    Pause(u32),
+    Prosody(ProsodyKind, Level),
+    Other {
+        name: String,
+        args: Vec<(String, String)>,
+    },
+}
+
+impl Command {
+    fn parse(inner: &str) -> Result<Self, ParseErrorKind> {
+        let mut words = inner.split_whitespace();
+        let name = words.next().ok_or(ParseErrorKind::EmptyCommand)?;
+
+        let args = words
+            .map(|word| {
+                word.split_once('=')
+                    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                    .ok_or_else(|| ParseErrorKind::MalformedArgument(word.to_owned()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let single = match args.as_slice() {
+            [(key, value)] => Some((key.as_str(), value.as_str())),
+            _ => None,
+        };
+
+        let command = match (name, single) {
+            ("Irq", Some(("MARK", mark))) => match mark.strip_prefix(AUTO_BOOKMARK_PREFIX) {
+                Some(position) => match position.parse() {
+                    Ok(position) => Self::AutoBookmark(position),
+                    Err(_) => Self::Bookmark(mark.to_owned()),
+                },
+                None => Self::Bookmark(mark.to_owned()),
+            },
+            ("Pau", Some(("MSEC", msec))) => match msec.parse() {
+                Ok(msec) => Self::Pause(msec),
+                Err(_) => return Err(ParseErrorKind::InvalidValue(msec.to_owned())),
+            },
+            (name, Some((key, value)))
+                if ProsodyKind::from_name(name).is_some_and(|kind| kind.key() == key) =>
+            {
+                let kind = ProsodyKind::from_name(name).unwrap();
+                match value.parse() {
+                    Ok(level) => Self::Prosody(kind, level),
+                    Err(_) => return Err(ParseErrorKind::InvalidValue(value.to_owned())),
+                }
+            }
+            _ => Self::Other {
+                name: name.to_owned(),
+                args,
+            },
+        };
+
+        Ok(command)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bookmark(mark) => write!(f, "(Irq MARK={mark})"),
+            Self::AutoBookmark(position) => {
+                write!(f, "(Irq MARK={AUTO_BOOKMARK_PREFIX}{position})")
+            }
+            Self::Pause(msec) => write!(f, "(Pau MSEC={msec})"),
+            Self::Prosody(kind, level) => write!(f, "({} {}={level})", kind.name(), kind.key()),
+            Self::Other { name, args } => {
+                write!(f, "({name}")?;
+                for (key, value) in args {
+                    write!(f, " {key}={value}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// 連続するカナ
+    Kana(String),
+    Accent(char),
+    Symbol(char),
+    Boundary(Boundary),
+    /// `<S>` などの `<` `>` の中身
+    Marker(String),
+    Command(Command),
+    Other(char),
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kana(kana) => write!(f, "{kana}"),
+            Self::Accent(c) | Self::Symbol(c) | Self::Other(c) => write!(f, "{c}"),
+            Self::Boundary(boundary) => write!(f, "{}", boundary.as_char()),
+            Self::Marker(marker) => write!(f, "<{marker}>"),
+            Self::Command(command) => write!(f, "{command}"),
+        }
+    }
+}
+
+/// `span` は元の文字列上のバイト範囲 (編集で追加されたノードは空範囲)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned {
+    pub node: Node,
+    pub span: Range<usize>,
+    /// コマンドの元の文字列 (括弧を含む)。`node` が同じコマンドを表す間はこれを書き戻す
+    pub raw: Option<String>,
+}
+
+impl Spanned {
+    pub fn new(node: Node, span: Range<usize>) -> Self {
+        Self {
+            node,
+            span,
+            raw: None,
+        }
+    }
+
+    /// `raw` が今の `node` と同じコマンドを表すならそれを返す
+    fn unedited_raw(&self) -> Option<&str> {
+        let Node::Command(command) = &self.node else {
+            return None;
+        };
+        let raw = self.raw.as_deref()?;
+        let inner = raw.strip_prefix('(')?.strip_suffix(')')?;
+
+        (Command::parse(inner).ok().as_ref() == Some(command)).then_some(raw)
+    }
+}
+
+impl fmt::Display for Spanned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.unedited_raw() {
+            Some(raw) => write!(f, "{raw}"),
+            None => write!(f, "{}", self.node),
+        }
+    }
+}
+
+/// アクセント句 (区切り記号で挟まれたノード列)
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccentPhrase {
+    /// `AiKana::nodes` 上の範囲 (末尾の区切り記号を含まない)
+    pub nodes: Range<usize>,
+    /// 句を終える区切り記号の `AiKana::nodes` 上の位置
+    pub boundary: Option<usize>,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AiKana {
+    pub nodes: Vec<Spanned>,
+}
+
+impl AiKana {
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        let mut nodes: Vec<Spanned> = vec![];
+        let mut chars = s.char_indices().peekable();
+
+        while let Some((start, c)) = chars.next() {
+            let node = match c {
+                '<' | '(' => {
+                    let close = if c == '<' { '>' } else { ')' };
+                    let inner_start = start + c.len_utf8();
+                    let mut end = None;
+
+                    for (i, c) in chars.by_ref() {
+                        if c == close {
+                            end = Some(i);
+                            break;
+                        }
+                        if matches!(c, '<' | '(') {
+                            return Err(ParseError {
+                                kind: ParseErrorKind::Nested(c),
+                                span: i..i + c.len_utf8(),
+                            });
+                        }
+                    }
+
+                    let Some(end) = end else {
+                        return Err(ParseError {
+                            kind: ParseErrorKind::Unterminated(c),
+                            span: start..s.len(),
+                        });
+                    };
+
+                    let inner = &s[inner_start..end];
+                    let span = start..end + 1;
+
+                    let spanned = if c == '<' {
+                        Spanned::new(Node::Marker(inner.to_owned()), span)
+                    } else {
+                        let command = Command::parse(inner).map_err(|kind| ParseError {
+                            kind,
+                            span: span.clone(),
+                        })?;
+                        Spanned {
+                            node: Node::Command(command),
+                            raw: Some(s[span.clone()].to_owned()),
+                            span,
+                        }
+                    };
+
+                    nodes.push(spanned);
+                    continue;
+                }
+                '>' | ')' => {
+                    return Err(ParseError {
+                        kind: ParseErrorKind::Unopened(c),
+                        span: start..start + 1,
+                    });
+                }
+                c if is_kana(c) => {
+                    let mut end = start + c.len_utf8();
+                    let mut kana = String::from(c);
+
+                    while let Some(&(i, c)) = chars.peek() {
+                        if !is_kana(c) {
+                            break;
+                        }
+                        kana.push(c);
+                        end = i + c.len_utf8();
+                        chars.next();
+                    }
+
+                    nodes.push(Spanned::new(Node::Kana(kana), start..end));
+                    continue;
+                }
+                c if is_accent(c) => Node::Accent(c),
+                c if is_symbol(c) => Node::Symbol(c),
+                c => match Boundary::from_char(c) {
+                    Some(boundary) => Node::Boundary(boundary),
+                    None => Node::Other(c),
+                },
+            };
+
+            nodes.push(Spanned::new(node, start..start + c.len_utf8()));
+        }
+
+        Ok(Self { nodes })
+    }
+
+    pub fn from_sjis(bytes: &[u8]) -> Result<Self, ParseError> {
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        let (s, _encoding, _errors) = SHIFT_JIS.decode(&bytes[..len]);
+        Self::parse(&s)
+    }
+
+    /// `text_to_speech` にそのまま渡せる SJIS 文字列
+    pub fn to_cstring(&self) -> Result<CString, EncodeError> {
+        let s = self.to_string();
+        let (bytes, _encoding, errors) = SHIFT_JIS.encode(&s);
+
+        if errors {
+            let c = s
+                .chars()
+                .find(|c| SHIFT_JIS.encode(c.encode_utf8(&mut [0; 4])).2)
+                .unwrap_or(char::REPLACEMENT_CHARACTER);
+            return Err(EncodeError::Unmappable(c));
+        }
+
+        CString::new(bytes.into_owned()).map_err(|_| EncodeError::Nul)
+    }
+
+    pub fn accent_phrases(&self) -> Vec<AccentPhrase> {
+        let mut phrases = vec![];
+        let mut start = 0;
+
+        for (i, spanned) in self.nodes.iter().enumerate() {
+            if let Node::Boundary(_) = spanned.node {
+                phrases.push(self.phrase(start..i, Some(i)));
+                start = i + 1;
+            }
+        }
+
+        if start < self.nodes.len() {
+            phrases.push(self.phrase(start..self.nodes.len(), None));
+        }
+
+        phrases
+            .into_iter()
+            .filter(|p| {
+                self.nodes[p.nodes.clone()]
+                    .iter()
+                    .any(|n| matches!(n.node, Node::Kana(_)))
+            })
+            .collect()
+    }
+
+    fn phrase(&self, nodes: Range<usize>, boundary: Option<usize>) -> AccentPhrase {
+        let last = boundary.unwrap_or(nodes.end.saturating_sub(1));
+        let span = match (self.nodes.get(nodes.start), self.nodes.get(last)) {
+            (Some(first), Some(last)) => first.span.start..last.span.end,
+            _ => 0..0,
+        };
+
+        AccentPhrase {
+            nodes,
+            boundary,
+            span,
+        }
+    }
+
+    /// 句に含まれるカナ
+    pub fn phrase_kana(&self, phrase: &AccentPhrase) -> String {
+        self.nodes[phrase.nodes.clone()]
+            .iter()
+            .filter_map(|n| match &n.node {
+                Node::Kana(kana) => Some(kana.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// 句の先頭から数えたアクセント記号の直前までのモーラ数 (アクセント記号が無ければ `None`)
+    pub fn phrase_accent(&self, phrase: &AccentPhrase) -> Option<usize> {
+        let mut morae = 0;
+
+        for spanned in &self.nodes[phrase.nodes.clone()] {
+            match &spanned.node {
+                Node::Kana(kana) => morae += count_morae(kana),
+                Node::Accent(_) => return Some(morae),
+                _ => (),
+            }
+        }
+
+        None
+    }
+}
+
+impl FromStr for AiKana {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for AiKana {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for spanned in &self.nodes {
+            write!(f, "{spanned}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// 閉じられていない `<` `(`
+    Unterminated(char),
+    /// 対応する開き括弧の無い `>` `)`
+    Unopened(char),
+    /// `<` `(` の中に現れた `<` `(`
+    Nested(char),
+    EmptyCommand,
+    MalformedArgument(String),
+    InvalidValue(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Range<usize>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::Unterminated(c) => write!(f, "unterminated {c:?}")?,
+            ParseErrorKind::Unopened(c) => write!(f, "unmatched {c:?}")?,
+            ParseErrorKind::Nested(c) => write!(f, "unexpected {c:?} inside a tag")?,
+            ParseErrorKind::EmptyCommand => write!(f, "empty command")?,
+            ParseErrorKind::MalformedArgument(arg) => write!(f, "malformed argument: {arg:?}")?,
+            ParseErrorKind::InvalidValue(value) => write!(f, "invalid value: {value:?}")?,
+        }
+        write!(f, " at {}..{}", self.span.start, self.span.end)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    Unmappable(char),
+    Nul,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unmappable(c) => write!(f, "{c:?} cannot be encoded in Shift_JIS"),
+            Self::Nul => write!(f, "AIKana contains NUL"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &[(&str, &str)] = &[
+        (
+            "sentence",
+            include_str!("../tests/fixtures/aikana/sentence.txt"),
+        ),
+        (
+            "pauses",
+            include_str!("../tests/fixtures/aikana/pauses.txt"),
+        ),
+        (
+            "prosody",
+            include_str!("../tests/fixtures/aikana/prosody.txt"),
+        ),
+        (
+            "bookmarks",
+            include_str!("../tests/fixtures/aikana/bookmarks.txt"),
+        ),
+        (
+            "unknown",
+            include_str!("../tests/fixtures/aikana/unknown.txt"),
+        ),
+    ];
+
+    fn commands(kana: &AiKana) -> Vec<&Command> {
+        kana.nodes
+            .iter()
+            .filter_map(|spanned| match &spanned.node {
+                Node::Command(command) => Some(command),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_fixtures() {
+        for (name, s) in FIXTURES {
+            let kana = AiKana::parse(s).unwrap_or_else(|e| panic!("{name}: {e}"));
+            assert_eq!(kana.to_string(), *s, "{name}");
+        }
+    }
+
+    #[test]
+    fn round_trips_fixtures_as_sjis() {
+        for (name, s) in FIXTURES {
+            let (bytes, _, _) = SHIFT_JIS.encode(s);
+            let kana = AiKana::from_sjis(&bytes).unwrap();
+            assert_eq!(kana.to_cstring().unwrap().as_bytes(), &bytes[..], "{name}");
+        }
+    }
+
+    #[test]
+    fn keeps_original_spelling() {
+        for s in [
+            "(Pau MSEC=0100)",
+            "(Irq MARK=_AI@007)",
+            "(Foo  A=1)",
+            "( Pau MSEC=300 )",
+        ] {
+            assert_eq!(AiKana::parse(s).unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn parses_typed_values() {
+        let kana = AiKana::parse(FIXTURES[1].1).unwrap();
+        let pauses = commands(&kana);
+
+        assert_eq!(pauses[0], &Command::AutoBookmark(0));
+        assert_eq!(pauses[2], &Command::Pause(200));
+        assert_eq!(pauses[3], &Command::Pause(100));
+
+        let kana = AiKana::parse(FIXTURES[4].1).unwrap();
+        assert_eq!(
+            commands(&kana)[0],
+            &Command::Other {
+                name: "Foo".to_owned(),
+                args: vec![("A".to_owned(), "1".to_owned())],
+            }
+        );
+    }
+
+    #[test]
+    fn writes_edited_commands_normalized() {
+        let mut kana = AiKana::parse("<S>(Pau MSEC=0100)(Irq MARK=_AI@007)ア<F>").unwrap();
+        kana.nodes[1].node = Node::Command(Command::Pause(200));
+
+        assert_eq!(kana.to_string(), "<S>(Pau MSEC=200)(Irq MARK=_AI@007)ア<F>");
+    }
+}
@@ -0,0 +1,378 @@
+use std::fmt;
+use std::ops::Range;
+
+use super::*;
+
+impl AiKana {
+    /// `phrase` 番目のアクセント句のアクセント核を先頭から `mora` モーラ目の後ろに移す (`None` で平板型)
+    pub fn set_accent(&mut self, phrase: usize, mora: Option<usize>) -> Result<(), EditError> {
+        let target = self.accent_phrase(phrase)?;
+
+        // 範囲外なら句を変更せずにエラーを返す
+        if let Some(mora) = mora {
+            let len = self.phrase_morae(&target);
+            if mora > len || len == 0 {
+                return Err(EditError::MoraOutOfRange { mora, len });
+            }
+        }
+
+        let accent = self.nodes[target.nodes.clone()]
+            .iter()
+            .find_map(|n| match n.node {
+                Node::Accent(c) => Some(c),
+                _ => None,
+            })
+            .unwrap_or('\'');
+
+        let mut removed = 0;
+        let mut i = target.nodes.start;
+        while i < target.nodes.end - removed {
+            if let Node::Accent(_) = self.nodes[i].node {
+                self.nodes.remove(i);
+                removed += 1;
+            } else {
+                i += 1;
+            }
+        }
+
+        let Some(mora) = mora else {
+            return Ok(());
+        };
+
+        let target = self.accent_phrase(phrase)?;
+        let index = self.mora_insertion_point(&target, mora)?;
+        self.insert_node(index, Node::Accent(accent));
+
+        Ok(())
+    }
+
+    /// `phrase` 番目のアクセント句 (区切り記号があればその後ろ) に `msec` ミリ秒のポーズを入れる
+    pub fn insert_pause(&mut self, phrase: usize, msec: u32) -> Result<(), EditError> {
+        let target = self.accent_phrase(phrase)?;
+        let index = target.boundary.map(|i| i + 1).unwrap_or(target.nodes.end);
+        self.insert_node(index, Node::Command(Command::Pause(msec)));
+
+        Ok(())
+    }
+
+    /// `phrases` の範囲のアクセント句に音量・話速・高さを設定し、範囲の後ろでは元の値に戻す
+    pub fn set_prosody(
+        &mut self,
+        phrases: Range<usize>,
+        kind: ProsodyKind,
+        level: Level,
+    ) -> Result<(), EditError> {
+        if phrases.is_empty() {
+            return Ok(());
+        }
+
+        let first = self.accent_phrase(phrases.start)?;
+        let last = self.accent_phrase(phrases.end - 1)?;
+
+        let restore = self.prosody_at(last.nodes.end, kind);
+
+        // 範囲内の同じ種類の指定は新しい値で置き換える
+        for i in (first.nodes.start..last.nodes.end).rev() {
+            if let Node::Command(Command::Prosody(k, _)) = self.nodes[i].node {
+                if k == kind {
+                    self.nodes.remove(i);
+                }
+            }
+        }
+
+        // `<S>` `<F>` などの文の区切りの内側に入れる
+        let first = self.accent_phrase(phrases.start)?;
+        let last = self.accent_phrase(phrases.end - 1)?;
+        let start = self.first_kana(&first);
+        let end = last
+            .nodes
+            .clone()
+            .rev()
+            .find(|&i| !matches!(self.nodes[i].node, Node::Marker(_)))
+            .map_or(last.nodes.end, |i| i + 1);
+
+        self.insert_node(end, Node::Command(Command::Prosody(kind, restore)));
+        self.insert_node(start, Node::Command(Command::Prosody(kind, level)));
+
+        Ok(())
+    }
+
+    /// `index` の直前で有効な `kind` の値 (指定が無ければ 1.0)
+    pub fn prosody_at(&self, index: usize, kind: ProsodyKind) -> Level {
+        self.nodes[..index.min(self.nodes.len())]
+            .iter()
+            .rev()
+            .find_map(|n| match n.node {
+                Node::Command(Command::Prosody(k, level)) if k == kind => Some(level),
+                _ => None,
+            })
+            .unwrap_or(Level::new(1.0))
+    }
+
+    /// `phrase` 番目と次のアクセント句を結合する (アクセント核は先に現れるものだけを残す)
+    ///
+    /// 2つの句の間に `<F>` `<S>` などの文の区切りがある場合は `EditError::AcrossSentences`
+    pub fn join_phrases(&mut self, phrase: usize) -> Result<(), EditError> {
+        let phrases = self.accent_phrases();
+        let target = phrases.get(phrase).ok_or(EditError::PhraseOutOfRange {
+            index: phrase,
+            len: phrases.len(),
+        })?;
+        let next = phrases
+            .get(phrase + 1)
+            .ok_or(EditError::NoNextPhrase(phrase))?;
+
+        let between = self.last_kana(target) + 1..self.first_kana(next);
+        if self.nodes[between]
+            .iter()
+            .any(|n| matches!(n.node, Node::Marker(_)))
+        {
+            return Err(EditError::AcrossSentences(phrase));
+        }
+
+        let has_accent = self.nodes[target.nodes.clone()]
+            .iter()
+            .any(|n| matches!(n.node, Node::Accent(_)));
+
+        // 後ろから消すことで手前の添字をずらさない
+        if has_accent {
+            for i in next.nodes.clone().rev() {
+                if let Node::Accent(_) = self.nodes[i].node {
+                    self.nodes.remove(i);
+                }
+            }
+        }
+
+        for i in (target.nodes.end..next.nodes.start).rev() {
+            if let Node::Boundary(_) = self.nodes[i].node {
+                self.nodes.remove(i);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `phrase` 番目のアクセント句を先頭から `mora` モーラ目の後ろで分割する
+    pub fn split_phrase(
+        &mut self,
+        phrase: usize,
+        mora: usize,
+        boundary: Boundary,
+    ) -> Result<(), EditError> {
+        let target = self.accent_phrase(phrase)?;
+        let len = self.phrase_morae(&target);
+
+        if mora == 0 || mora >= len {
+            return Err(EditError::MoraOutOfRange { mora, len });
+        }
+
+        let index = self.mora_insertion_point(&target, mora)?;
+        self.insert_node(index, Node::Boundary(boundary));
+
+        Ok(())
+    }
+
+    pub fn phrase_morae(&self, phrase: &AccentPhrase) -> usize {
+        count_morae(&self.phrase_kana(phrase))
+    }
+
+    /// 句の最初のカナの添字
+    fn first_kana(&self, phrase: &AccentPhrase) -> usize {
+        phrase
+            .nodes
+            .clone()
+            .find(|&i| matches!(self.nodes[i].node, Node::Kana(_)))
+            .unwrap_or(phrase.nodes.start)
+    }
+
+    /// 句の最後のカナの添字
+    fn last_kana(&self, phrase: &AccentPhrase) -> usize {
+        phrase
+            .nodes
+            .clone()
+            .rev()
+            .find(|&i| matches!(self.nodes[i].node, Node::Kana(_)))
+            .unwrap_or(phrase.nodes.start)
+    }
+
+    fn accent_phrase(&self, index: usize) -> Result<AccentPhrase, EditError> {
+        let mut phrases = self.accent_phrases();
+        let len = phrases.len();
+
+        if index >= len {
+            return Err(EditError::PhraseOutOfRange { index, len });
+        }
+
+        Ok(phrases.swap_remove(index))
+    }
+
+    /// 追加したノードには挿入位置の空範囲を割り当てる
+    fn insert_node(&mut self, index: usize, node: Node) {
+        let position = match (index.checked_sub(1), self.nodes.get(index)) {
+            (Some(prev), _) => self.nodes[prev].span.end,
+            (None, Some(next)) => next.span.start,
+            (None, None) => 0,
+        };
+
+        self.nodes
+            .insert(index, Spanned::new(node, position..position));
+    }
+
+    /// 句の先頭から `mora` モーラ目の直後に当たるノードの添字 (必要ならカナを分割する)
+    fn mora_insertion_point(
+        &mut self,
+        phrase: &AccentPhrase,
+        mora: usize,
+    ) -> Result<usize, EditError> {
+        let mut morae = 0;
+        let mut last_kana_end = None;
+
+        for i in phrase.nodes.clone() {
+            let Node::Kana(kana) = &self.nodes[i].node else {
+                continue;
+            };
+
+            let len = count_morae(kana);
+
+            if morae + len > mora {
+                if morae == mora {
+                    return Ok(i);
+                }
+
+                self.split_kana(i, mora - morae);
+                return Ok(i + 1);
+            }
+
+            morae += len;
+            last_kana_end = Some(i + 1);
+        }
+
+        match last_kana_end {
+            Some(index) if morae == mora => Ok(index),
+            _ => Err(EditError::MoraOutOfRange { mora, len: morae }),
+        }
+    }
+
+    fn split_kana(&mut self, index: usize, mora: usize) {
+        let Node::Kana(kana) = &self.nodes[index].node else {
+            return;
+        };
+
+        let mut morae = 0;
+        let offset = kana
+            .char_indices()
+            .find(|(_, c)| {
+                if is_small_kana(*c) {
+                    return false;
+                }
+                morae += 1;
+                morae > mora
+            })
+            .map(|(i, _)| i)
+            .unwrap_or(kana.len());
+
+        let (head, tail) = kana.split_at(offset);
+        let (head, tail) = (head.to_owned(), tail.to_owned());
+
+        let span = self.nodes[index].span.clone();
+        let middle = (span.start + offset).min(span.end);
+
+        self.nodes[index] = Spanned::new(Node::Kana(head), span.start..middle);
+        self.nodes
+            .insert(index + 1, Spanned::new(Node::Kana(tail), middle..span.end));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditError {
+    PhraseOutOfRange {
+        index: usize,
+        len: usize,
+    },
+    MoraOutOfRange {
+        mora: usize,
+        len: usize,
+    },
+    NoNextPhrase(usize),
+    /// 句の間に文の区切りがあり結合できない
+    AcrossSentences(usize),
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PhraseOutOfRange { index, len } => {
+                write!(f, "accent phrase {index} is out of range ({len} phrases)")
+            }
+            Self::MoraOutOfRange { mora, len } => {
+                write!(f, "mora {mora} is out of range ({len} morae)")
+            }
+            Self::NoNextPhrase(index) => write!(f, "accent phrase {index} is the last one"),
+            Self::AcrossSentences(index) => write!(
+                f,
+                "accent phrase {index} and the next one are in different sentences"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_accent_out_of_range_keeps_phrase() {
+        let s = "<S>コ^ンニチワ|セ'カイ<F>";
+        let mut kana = AiKana::parse(s).unwrap();
+
+        assert_eq!(
+            kana.set_accent(0, Some(6)),
+            Err(EditError::MoraOutOfRange { mora: 6, len: 5 })
+        );
+        assert_eq!(kana.to_string(), s);
+
+        kana.set_accent(0, Some(3)).unwrap();
+        assert_eq!(kana.to_string(), "<S>コンニ^チワ|セ'カイ<F>");
+    }
+
+    #[test]
+    fn set_prosody_replaces_inner_commands() {
+        let mut kana =
+            AiKana::parse("<S>(Vol ABSLEVEL=1.20)ア|(Vol ABSLEVEL=1.50)イ|ウ<F>").unwrap();
+
+        kana.set_prosody(0..2, ProsodyKind::Volume, Level::new(0.8))
+            .unwrap();
+
+        assert_eq!(
+            kana.to_string(),
+            "<S>(Vol ABSLEVEL=0.80)ア|イ(Vol ABSLEVEL=1.50)|ウ<F>"
+        );
+    }
+
+    #[test]
+    fn set_prosody_starts_after_bookmark() {
+        let mut kana = AiKana::parse("<S>(Irq MARK=_AI@0)ア|イ<F>").unwrap();
+
+        kana.set_prosody(1..2, ProsodyKind::Speed, Level::new(1.2))
+            .unwrap();
+
+        assert_eq!(
+            kana.to_string(),
+            "<S>(Irq MARK=_AI@0)ア|(Spd ABSSPEED=1.20)イ(Spd ABSSPEED=1.00)<F>"
+        );
+    }
+
+    #[test]
+    fn join_phrases_rejects_sentence_boundary() {
+        let s = "<S>ア|イ。<F>\r\n<S>ウ|エ<F>";
+        let mut kana = AiKana::parse(s).unwrap();
+
+        assert_eq!(kana.join_phrases(1), Err(EditError::AcrossSentences(1)));
+        assert_eq!(kana.to_string(), s);
+
+        kana.join_phrases(2).unwrap();
+        assert_eq!(kana.to_string(), "<S>ア|イ。<F>\r\n<S>ウエ<F>");
+    }
+}
@@ -0,0 +1,444 @@
+use std::fmt;
+use std::ops::{Range, RangeInclusive};
+
+use super::*;
+use crate::source_map::SourceMap;
+
+/// エディタの設定範囲に合わせた値の範囲
+pub const PAUSE_MSEC_RANGE: RangeInclusive<u32> = 0..=10000;
+pub const VOLUME_RANGE: RangeInclusive<f32> = 0.0..=2.0;
+pub const SPEED_RANGE: RangeInclusive<f32> = 0.5..=4.0;
+pub const PITCH_RANGE: RangeInclusive<f32> = 0.5..=2.0;
+pub const EMPHASIS_RANGE: RangeInclusive<f32> = 0.0..=2.0;
+
+impl ProsodyKind {
+    pub fn range(&self) -> RangeInclusive<f32> {
+        match self {
+            Self::Volume => VOLUME_RANGE,
+            Self::Speed => SPEED_RANGE,
+            Self::Pitch => PITCH_RANGE,
+            Self::Emphasis => EMPHASIS_RANGE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintKind {
+    Unterminated(char),
+    Unopened(char),
+    /// `(Vol>` のように開き括弧と対にならない閉じ括弧
+    Mismatched {
+        open: char,
+        close: char,
+    },
+    Nested(char),
+    InvalidCharacter(char),
+    NotShiftJis(char),
+    MalformedCommand,
+    UnknownCommand(String),
+    OutOfRange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: LintKind,
+    pub message: String,
+    /// UTF-8 上のバイト範囲
+    pub span: Range<usize>,
+    /// 文字単位の範囲
+    pub chars: Range<usize>,
+    /// SJIS に変換した文字列 (エンジンに渡すもの) 上のバイト範囲
+    pub sjis: Range<usize>,
+    /// `span` をこの文字列で置き換えると解消する
+    pub suggestion: Option<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+
+        write!(
+            f,
+            "{severity}: {} (char {}..{}, byte {}..{}, sjis byte {}..{})",
+            self.message,
+            self.chars.start,
+            self.chars.end,
+            self.span.start,
+            self.span.end,
+            self.sjis.start,
+            self.sjis.end,
+        )?;
+
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "; try {suggestion:?}")?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Linter<'a> {
+    source: &'a str,
+    offsets: SourceMap,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn push(
+        &mut self,
+        severity: Severity,
+        kind: LintKind,
+        span: Range<usize>,
+        message: String,
+        suggestion: Option<String>,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            kind,
+            message,
+            chars: self.offsets.char_index(span.start)..self.offsets.char_index(span.end),
+            sjis: self.offsets.sjis_offset(span.start)..self.offsets.sjis_offset(span.end),
+            span,
+            suggestion,
+        });
+    }
+
+    fn run(&mut self) {
+        let source = self.source;
+        let mut open: Option<(usize, char)> = None;
+
+        for (i, c) in source.char_indices() {
+            let span = i..i + c.len_utf8();
+
+            if SHIFT_JIS.encode(c.encode_utf8(&mut [0; 4])).2 {
+                self.push(
+                    Severity::Error,
+                    LintKind::NotShiftJis(c),
+                    span.clone(),
+                    format!("{c:?} cannot be encoded in Shift_JIS"),
+                    Some(String::new()),
+                );
+                continue;
+            }
+
+            match (open, c) {
+                (Some((start, open_char)), '<' | '(') => {
+                    self.push(
+                        Severity::Error,
+                        LintKind::Nested(c),
+                        span,
+                        format!("{c:?} inside {open_char:?} opened at byte {start}"),
+                        Some(String::new()),
+                    );
+                }
+                (Some((start, open_char)), '>' | ')') => {
+                    let expected = if open_char == '<' { '>' } else { ')' };
+
+                    if c != expected {
+                        self.push(
+                            Severity::Error,
+                            LintKind::Mismatched {
+                                open: open_char,
+                                close: c,
+                            },
+                            span,
+                            format!("{c:?} does not close {open_char:?} opened at byte {start}"),
+                            Some(expected.to_string()),
+                        );
+                    }
+
+                    if c == ')' && open_char == '(' {
+                        self.command(start..i + 1);
+                    }
+
+                    open = None;
+                }
+                (Some(_), _) => (),
+                (None, '<' | '(') => open = Some((i, c)),
+                (None, '>' | ')') => {
+                    self.push(
+                        Severity::Error,
+                        LintKind::Unopened(c),
+                        span,
+                        format!("unmatched {c:?}"),
+                        Some(String::new()),
+                    );
+                }
+                (None, c) => self.character(c, span),
+            }
+        }
+
+        if let Some((start, c)) = open {
+            let close = if c == '<' { '>' } else { ')' };
+            self.push(
+                Severity::Error,
+                LintKind::Unterminated(c),
+                start..source.len(),
+                format!("unterminated {c:?}"),
+                Some(format!("{}{close}", &source[start..])),
+            );
+        }
+    }
+
+    fn character(&mut self, c: char, span: Range<usize>) {
+        if let 'ぁ'..='ゖ' | 'ゝ' | 'ゞ' = c {
+            let katakana = char::from_u32(c as u32 + 0x60).unwrap_or(c);
+            self.push(
+                Severity::Error,
+                LintKind::InvalidCharacter(c),
+                span,
+                format!("hiragana {c:?} is not allowed in AIKana"),
+                Some(katakana.to_string()),
+            );
+            return;
+        }
+
+        if is_kana(c) || is_accent(c) || is_symbol(c) || Boundary::from_char(c).is_some() {
+            return;
+        }
+
+        let suggestion = if c.is_whitespace() {
+            Some(String::new())
+        } else {
+            None
+        };
+
+        self.push(
+            Severity::Error,
+            LintKind::InvalidCharacter(c),
+            span,
+            format!("{c:?} is not in the AIKana alphabet (convert the text with text_to_kana)"),
+            suggestion,
+        );
+    }
+
+    fn command(&mut self, span: Range<usize>) {
+        let inner = &self.source[span.start + 1..span.end - 1];
+
+        let command = match Command::parse(inner) {
+            Ok(command) => command,
+            Err(e) => {
+                let message = match e {
+                    ParseErrorKind::EmptyCommand => "empty command".to_owned(),
+                    ParseErrorKind::MalformedArgument(arg) => {
+                        format!("malformed argument {arg:?} (expected KEY=VALUE)")
+                    }
+                    ParseErrorKind::InvalidValue(value) => format!("invalid value {value:?}"),
+                    _ => "malformed command".to_owned(),
+                };
+                self.push(
+                    Severity::Error,
+                    LintKind::MalformedCommand,
+                    span,
+                    message,
+                    None,
+                );
+                return;
+            }
+        };
+
+        match command {
+            Command::Pause(msec) if !PAUSE_MSEC_RANGE.contains(&msec) => {
+                let clamped = msec.clamp(*PAUSE_MSEC_RANGE.start(), *PAUSE_MSEC_RANGE.end());
+                self.push(
+                    Severity::Error,
+                    LintKind::OutOfRange,
+                    span,
+                    format!(
+                        "pause {msec} ms is out of range ({}..={})",
+                        PAUSE_MSEC_RANGE.start(),
+                        PAUSE_MSEC_RANGE.end()
+                    ),
+                    Some(Command::Pause(clamped).to_string()),
+                );
+            }
+            Command::Prosody(kind, level) if !kind.range().contains(&level.value) => {
+                let range = kind.range();
+                let clamped = Level {
+                    value: level.value.clamp(*range.start(), *range.end()),
+                    ..level
+                };
+                self.push(
+                    Severity::Error,
+                    LintKind::OutOfRange,
+                    span,
+                    format!(
+                        "{} {} is out of range ({}..={})",
+                        kind.name(),
+                        level,
+                        range.start(),
+                        range.end()
+                    ),
+                    Some(Command::Prosody(kind, clamped).to_string()),
+                );
+            }
+            Command::Other { name, .. } => {
+                self.push(
+                    Severity::Warning,
+                    LintKind::UnknownCommand(name.clone()),
+                    span,
+                    format!("unknown command {name:?}"),
+                    None,
+                );
+            }
+            _ => (),
+        }
+    }
+}
+
+/// `s` を検査し、見つかった問題を出現順に全て返す
+pub fn lint(s: &str) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        source: s,
+        offsets: SourceMap::new(s),
+        diagnostics: vec![],
+    };
+
+    linter.run();
+    linter.diagnostics
+}
+
+/// `Severity::Error` が一つも無ければ構文木を返す
+pub fn validate(s: &str) -> Result<AiKana, Vec<Diagnostic>> {
+    let diagnostics = lint(s);
+
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return Err(diagnostics);
+    }
+
+    AiKana::parse(s).map_err(|e| {
+        let offsets = SourceMap::new(s);
+        vec![Diagnostic {
+            severity: Severity::Error,
+            kind: LintKind::MalformedCommand,
+            message: e.to_string(),
+            chars: offsets.char_index(e.span.start)..offsets.char_index(e.span.end),
+            sjis: offsets.sjis_offset(e.span.start)..offsets.sjis_offset(e.span.end),
+            span: e.span,
+            suggestion: None,
+        }]
+    })
+}
+
+/// 全ての提案を適用した文字列
+pub fn apply_suggestions(s: &str, diagnostics: &[Diagnostic]) -> String {
+    let mut fixes: Vec<_> = diagnostics
+        .iter()
+        .filter_map(|d| d.suggestion.as_ref().map(|s| (d.span.clone(), s)))
+        .collect();
+    fixes.sort_by_key(|(span, _)| span.start);
+
+    let mut fixed = String::with_capacity(s.len());
+    let mut cursor = 0;
+
+    for (span, replacement) in fixes {
+        if span.start < cursor {
+            continue;
+        }
+        fixed.push_str(&s[cursor..span.start]);
+        fixed.push_str(replacement);
+        cursor = span.end;
+    }
+
+    fixed.push_str(&s[cursor..]);
+    fixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_mismatched_close() {
+        let diagnostics = lint("ア(Vol>イ");
+
+        assert_eq!(diagnostics.len(), 1);
+        let d = &diagnostics[0];
+        assert_eq!(
+            d.kind,
+            LintKind::Mismatched {
+                open: '(',
+                close: '>'
+            }
+        );
+        assert_eq!(d.span, 7..8);
+        assert_eq!(d.chars, 5..6);
+        assert_eq!(d.sjis, 6..7);
+        assert_eq!(d.suggestion.as_deref(), Some(")"));
+    }
+
+    #[test]
+    fn spans_over_mixed_text() {
+        let s = "コaか😀ン、";
+        let diagnostics = lint(s);
+
+        let spans: Vec<_> = diagnostics
+            .iter()
+            .map(|d| {
+                (
+                    d.kind.clone(),
+                    d.span.clone(),
+                    d.chars.clone(),
+                    d.sjis.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (LintKind::InvalidCharacter('a'), 3..4, 1..2, 2..3),
+                (LintKind::InvalidCharacter('か'), 4..7, 2..3, 3..5),
+                // SJIS にできない文字は SJIS 上の長さ 0
+                (LintKind::NotShiftJis('😀'), 7..11, 3..4, 5..5),
+            ]
+        );
+        assert_eq!(apply_suggestions(s, &diagnostics), "コaカン、");
+    }
+
+    #[test]
+    fn spans_of_commands_after_multibyte_text() {
+        let diagnostics = lint("ア漢(Pau MSEC=20000)");
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].kind, LintKind::InvalidCharacter('漢'));
+        assert_eq!(
+            (
+                &diagnostics[0].span,
+                &diagnostics[0].chars,
+                &diagnostics[0].sjis
+            ),
+            (&(3..6), &(1..2), &(2..4))
+        );
+        assert_eq!(diagnostics[1].kind, LintKind::OutOfRange);
+        assert_eq!(
+            (
+                &diagnostics[1].span,
+                &diagnostics[1].chars,
+                &diagnostics[1].sjis
+            ),
+            (&(6..22), &(2..18), &(4..20))
+        );
+        assert_eq!(
+            diagnostics[1].suggestion.as_deref(),
+            Some("(Pau MSEC=10000)")
+        );
+    }
+
+    #[test]
+    fn hiragana_is_not_kana() {
+        assert!(!is_kana('か'));
+        assert!(!is_small_kana('ゃ'));
+        assert_eq!(lint("ゝ")[0].suggestion.as_deref(), Some("ヽ"));
+        assert!(lint("<S>カ'ナ|ヽ<F>").is_empty());
+    }
+}
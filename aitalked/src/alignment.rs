@@ -0,0 +1,158 @@
+//! 入力テキスト・AIKana・合成時のイベントをまとめた、語ごとの時刻の対応表
+//!
+//! 語は `AUTO_BOOKMARK` で区切る。読みは AIKana 中の同じ位置の `(Irq MARK=_AI@n)` から次のものまでのカナ
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::aikana::{AiKana, Command, Node};
+use crate::job::SpeechEvent;
+use crate::source_map::SourceMap;
+use crate::timeline::{Segment, Timeline, Track};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlignedPhoneme {
+    pub phoneme: String,
+    pub start_ms: f64,
+    pub end_ms: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlignedWord {
+    pub text: String,
+    /// 入力テキスト上の文字単位の範囲
+    pub chars: Range<usize>,
+    pub reading: Option<String>,
+    pub start_ms: f64,
+    pub end_ms: f64,
+    pub phonemes: Vec<AlignedPhoneme>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Alignment {
+    pub text: String,
+    pub duration_ms: f64,
+    pub words: Vec<AlignedWord>,
+}
+
+/// `(Irq MARK=_AI@n)` ごとのカナ
+fn readings(kana: &AiKana) -> HashMap<u32, String> {
+    let mut readings = HashMap::new();
+    let mut current = None;
+
+    for node in &kana.nodes {
+        match &node.node {
+            Node::Command(Command::AutoBookmark(position)) => current = Some(*position),
+            Node::Kana(s) => {
+                if let Some(position) = current {
+                    readings
+                        .entry(position)
+                        .or_insert_with(String::new)
+                        .push_str(s);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    readings
+}
+
+fn all_kana(kana: &AiKana) -> String {
+    kana.nodes
+        .iter()
+        .filter_map(|n| match &n.node {
+            Node::Kana(s) => Some(s.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Alignment {
+    /// `source` は変換ジョブに渡した文字列か `RichText`、`kana` はその結果 (読み上げに使ったもの)
+    pub fn new(source: impl Into<SourceMap>, kana: Option<&AiKana>, timeline: &Timeline) -> Self {
+        let map = source.into();
+        let text = map.text();
+        let readings = kana.map(readings).unwrap_or_default();
+
+        let phonemes: Vec<_> = timeline.track(Track::Phoneme).collect();
+        let phonemes_in = |start: u64, end: u64| {
+            phonemes
+                .iter()
+                .filter(|s| start <= s.start && s.start < end)
+                .filter_map(|s| match &s.event {
+                    SpeechEvent::Phoneme(phoneme) => Some(AlignedPhoneme {
+                        phoneme: phoneme.trim().to_owned(),
+                        start_ms: timeline.tick_to_ms(s.start),
+                        end_ms: timeline.tick_to_ms(s.end),
+                    }),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let bookmarks: Vec<(&Segment, usize)> = timeline
+            .track(Track::AutoBookmark)
+            .filter_map(|s| match s.event {
+                SpeechEvent::AutoBookmark(position) => Some((s, position as usize)),
+                _ => None,
+            })
+            .collect();
+
+        let words = if bookmarks.is_empty() {
+            vec![AlignedWord {
+                text: text.to_owned(),
+                chars: 0..text.chars().count(),
+                reading: kana.map(all_kana),
+                start_ms: 0.0,
+                end_ms: timeline.tick_to_ms(timeline.end()),
+                phonemes: phonemes_in(0, u64::MAX),
+            }]
+        } else {
+            bookmarks
+                .iter()
+                .enumerate()
+                .map(|(i, &(segment, position))| {
+                    let next = bookmarks
+                        .get(i + 1)
+                        .map(|(_, next)| *next)
+                        .unwrap_or(map.sjis_len());
+                    let bytes = map.byte_range(position..next.max(position));
+                    let end = if i + 1 == bookmarks.len() {
+                        u64::MAX
+                    } else {
+                        segment.end
+                    };
+
+                    AlignedWord {
+                        text: text[bytes.clone()].to_owned(),
+                        chars: map.char_index(bytes.start)..map.char_index(bytes.end),
+                        reading: readings.get(&(position as u32)).cloned(),
+                        start_ms: timeline.tick_to_ms(segment.start),
+                        end_ms: timeline.tick_to_ms(segment.end),
+                        phonemes: phonemes_in(segment.start, end),
+                    }
+                })
+                .collect()
+        };
+
+        Self {
+            text: text.to_owned(),
+            duration_ms: timeline.tick_to_ms(timeline.end()),
+            words,
+        }
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json_string(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    #[cfg(feature = "json")]
+    pub fn from_json_str(s: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(s)
+    }
+}
@@ -1,8 +1,12 @@
 use std::ffi::{c_char, c_void, CStr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
 
 use libloading::{Library, Symbol};
 
 use crate::binding::*;
+use crate::model::*;
 
 #[derive(Debug)]
 pub(crate) struct AitalkedInner<'lib> {
@@ -32,6 +36,8 @@ pub(crate) struct AitalkedInner<'lib> {
     reload_phrase_dic: Symbol<'lib, unsafe extern "system" fn(*const c_char) -> ResultCode>,
     reload_word_dic: Symbol<'lib, unsafe extern "system" fn(*const c_char) -> ResultCode>,
     reload_symbol_dic: Symbol<'lib, unsafe extern "system" fn(*const c_char) -> ResultCode>,
+    param_lock: ParamLock,
+    hz_voice_db: AtomicU32,
 }
 
 impl<'lib> AitalkedInner<'lib> {
@@ -72,6 +78,8 @@ impl<'lib> AitalkedInner<'lib> {
             reload_phrase_dic,
             reload_word_dic,
             reload_symbol_dic,
+            param_lock: ParamLock::default(),
+            hz_voice_db: AtomicU32::new(0),
         })
     }
 }
@@ -83,7 +91,23 @@ pub struct Aitalked {
 
 impl Aitalked {
     pub unsafe fn init(&self, config: &AitalkedConfig) -> ResultCode {
-        (self.inner.init)(config)
+        let code = (self.inner.init)(config);
+
+        if code == ResultCode::SUCCESS {
+            self.inner
+                .hz_voice_db
+                .store(config.hz_voice_db, Ordering::Relaxed);
+        }
+
+        code
+    }
+
+    /// `init` に成功したときのサンプリング周波数
+    pub fn hz_voice_db(&self) -> Option<u32> {
+        match self.inner.hz_voice_db.load(Ordering::Relaxed) {
+            0 => None,
+            hz => Some(hz),
+        }
     }
 
     /// NOTE: Install DirectoryがCurrent Working Directoryでないと正常に動作しない
@@ -111,15 +135,78 @@ impl Aitalked {
         (self.inner.set_param)(tts_param)
     }
 
+    /// 話者数を問い合わせた上で、現在のパラメータを丸ごと取得する
+    pub unsafe fn get_boxed_param(&self) -> Result<BoxedTtsParam, ResultCode> {
+        let mut size = 0;
+
+        match self.get_param(std::ptr::null_mut(), &mut size) {
+            ResultCode::SUCCESS | ResultCode::INSUFFICIENT => (),
+            code => return Err(code),
+        }
+
+        let header_size = std::mem::size_of::<TtsParam>();
+        let speakers_len =
+            (size as usize).saturating_sub(header_size) / std::mem::size_of::<SpeakerParam>();
+
+        let mut param = BoxedTtsParam::new(speakers_len);
+
+        match self.get_param(param.tts_param_mut(), &mut size) {
+            ResultCode::SUCCESS => Ok(param),
+            code => Err(code),
+        }
+    }
+
+    /// `overrides` を適用し、戻り値の `ParamScope` が破棄されるまで他のスレッドのスコープを待たせる
+    ///
+    /// 同じスレッドで既にスコープ (`SpeechStream` などが保持するものを含む) がある場合は `ParamError::Busy`
+    pub unsafe fn param_scope(&self, overrides: &ParamOverrides) -> Result<ParamScope, ParamError> {
+        let (lock, saved, current) = self.inner.param_lock.begin(
+            overrides,
+            || self.get_boxed_param(),
+            |param| self.set_param(param),
+        )?;
+
+        Ok(ParamScope {
+            aitalked: *self,
+            saved,
+            current,
+            restored: false,
+            _lock: lock,
+        })
+    }
+
+    /// `f` の実行中だけ `overrides` を適用し、終了後 (パニック時を含む) に元のパラメータへ戻す
+    pub unsafe fn with_params<R>(
+        &self,
+        overrides: &ParamOverrides,
+        f: impl FnOnce(&Aitalked) -> R,
+    ) -> Result<R, ParamError> {
+        let scope = self.param_scope(overrides)?;
+        let r = f(self);
+        scope.restore()?;
+        Ok(r)
+    }
+
     pub unsafe fn text_to_kana(
         &self,
         job_id: &mut i32,
         user_data: *mut c_void,
         text: &CStr,
+    ) -> ResultCode {
+        self.text_to_kana_with_mode(job_id, user_data, text, JobInOut::PLAIN_TO_AIKANA)
+    }
+
+    /// `model_in_out` は `PLAIN_TO_AIKANA` か `AIKANA_TO_JEITA`
+    pub unsafe fn text_to_kana_with_mode(
+        &self,
+        job_id: &mut i32,
+        user_data: *mut c_void,
+        text: &CStr,
+        model_in_out: JobInOut,
     ) -> ResultCode {
         let job_param = JobParam {
             user_data,
-            model_in_out: JobInOut::PLAIN_TO_AIKANA,
+            model_in_out,
         };
 
         (self.inner.text_to_kana)(job_id, &job_param, text.as_ptr())
@@ -130,10 +217,21 @@ impl Aitalked {
         job_id: &mut i32,
         user_data: *mut c_void,
         text: &CStr,
+    ) -> ResultCode {
+        self.text_to_speech_with_mode(job_id, user_data, text, JobInOut::AIKANA_TO_WAVE)
+    }
+
+    /// `model_in_out` は `PLAIN_TO_WAVE` か `AIKANA_TO_WAVE` か `JEITA_TO_WAVE`
+    pub unsafe fn text_to_speech_with_mode(
+        &self,
+        job_id: &mut i32,
+        user_data: *mut c_void,
+        text: &CStr,
+        model_in_out: JobInOut,
     ) -> ResultCode {
         let job_param = JobParam {
             user_data,
-            model_in_out: JobInOut::AIKANA_TO_WAVE,
+            model_in_out,
         };
 
         (self.inner.text_to_speech)(job_id, &job_param, text.as_ptr())
@@ -215,3 +313,166 @@ impl Aitalked {
         (self.inner.reload_symbol_dic)(path)
     }
 }
+
+/// `param_scope` の排他制御
+#[derive(Debug, Default)]
+pub(crate) struct ParamLock {
+    lock: Mutex<()>,
+    /// `lock` を保持しているスレッド
+    owner: Mutex<Option<ThreadId>>,
+}
+
+impl ParamLock {
+    /// ロックを取得して `overrides` を適用したパラメータを設定し、(ロック, 元の値, 適用後の値) を返す
+    ///
+    /// 途中で失敗した場合はロックと所有スレッドの記録を解放してからエラーを返す
+    fn begin(
+        &self,
+        overrides: &ParamOverrides,
+        get_param: impl FnOnce() -> Result<BoxedTtsParam, ResultCode>,
+        set_param: impl FnOnce(&TtsParam) -> ResultCode,
+    ) -> Result<(ParamLockGuard<'_>, BoxedTtsParam, BoxedTtsParam), ParamError> {
+        let lock = self.acquire()?;
+
+        let saved = get_param()?;
+        let mut current = saved.clone();
+        overrides.apply_to(&mut current)?;
+
+        match set_param(current.tts_param()) {
+            ResultCode::SUCCESS => Ok((lock, saved, current)),
+            code => Err(code.into()),
+        }
+    }
+
+    fn acquire(&self) -> Result<ParamLockGuard<'_>, ParamError> {
+        if *self.owner() == Some(thread::current().id()) {
+            return Err(ParamError::Busy);
+        }
+
+        let lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        *self.owner() = Some(thread::current().id());
+
+        Ok(ParamLockGuard {
+            owner: &self.owner,
+            _lock: lock,
+        })
+    }
+
+    fn owner(&self) -> MutexGuard<'_, Option<ThreadId>> {
+        self.owner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// 破棄時に所有スレッドの記録を消してからロックを解放する
+#[derive(Debug)]
+struct ParamLockGuard<'a> {
+    owner: &'a Mutex<Option<ThreadId>>,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl Drop for ParamLockGuard<'_> {
+    fn drop(&mut self) {
+        *self.owner.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// `param_scope` で適用したパラメータを保持し、破棄時に元へ戻す
+///
+/// 内部で `MutexGuard` を保持するため `Send` ではない。作成したスレッドで破棄すること
+/// (これを保持する `SpeechStream` なども同様)
+#[derive(Debug)]
+pub struct ParamScope {
+    aitalked: Aitalked,
+    saved: BoxedTtsParam,
+    current: BoxedTtsParam,
+    restored: bool,
+    _lock: ParamLockGuard<'static>,
+}
+
+impl ParamScope {
+    /// スコープ内で有効なパラメータ
+    pub fn tts_param(&self) -> &TtsParam {
+        self.current.tts_param()
+    }
+
+    pub fn speakers(&self) -> &[SpeakerParam] {
+        self.current.speakers()
+    }
+
+    /// 明示的に元のパラメータへ戻し、その結果を返す
+    pub fn restore(mut self) -> Result<(), ResultCode> {
+        self.restored = true;
+
+        match unsafe { self.aitalked.set_param(self.saved.tts_param()) } {
+            ResultCode::SUCCESS => Ok(()),
+            code => Err(code),
+        }
+    }
+}
+
+impl Drop for ParamScope {
+    fn drop(&mut self) {
+        if !self.restored {
+            let _ = unsafe { self.aitalked.set_param(self.saved.tts_param()) };
+        }
+
+        // `_lock` はこの後に解放される
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::VoicePreset;
+    use crate::style::StyleRates;
+
+    #[test]
+    fn failed_begin_releases_owner() {
+        let lock = ParamLock::default();
+        let overrides = ParamOverrides {
+            voice: Some(VoicePreset {
+                voice_name: "missing".to_owned(),
+                volume: 1.0,
+                speed: 1.0,
+                pitch: 1.0,
+                range: 1.0,
+                pause_middle: 150,
+                pause_long: 370,
+                pause_sentence: 800,
+                styles: StyleRates::new(),
+            }),
+            ..Default::default()
+        };
+
+        let result = lock.begin(
+            &overrides,
+            || Ok(BoxedTtsParam::new(0)),
+            |_| ResultCode::SUCCESS,
+        );
+        assert!(matches!(result, Err(ParamError::Preset(_))));
+
+        let result = lock.begin(
+            &ParamOverrides::default(),
+            || Ok(BoxedTtsParam::new(0)),
+            |_| ResultCode::SUCCESS,
+        );
+        assert!(result.is_ok());
+        assert!(matches!(lock.acquire(), Err(ParamError::Busy)));
+
+        drop(result);
+        assert!(lock.acquire().is_ok());
+    }
+
+    #[test]
+    fn failed_set_param_releases_owner() {
+        let lock = ParamLock::default();
+
+        let result = lock.begin(
+            &ParamOverrides::default(),
+            || Ok(BoxedTtsParam::new(0)),
+            |_| ResultCode::INVALID_ARGUMENT,
+        );
+        assert!(matches!(result, Err(ParamError::Engine(_))));
+        assert!(lock.acquire().is_ok());
+    }
+}
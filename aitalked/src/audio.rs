@@ -0,0 +1,240 @@
+//! 合成した音声 (16 bit モノラル PCM) の保持と書き出し
+
+#[cfg(feature = "flac")]
+mod flac;
+mod loudness;
+#[cfg(feature = "ogg")]
+mod ogg;
+mod resample;
+mod trim;
+#[cfg(feature = "vorbis")]
+mod vorbis;
+mod wav;
+
+#[cfg(feature = "flac")]
+pub use flac::{VorbisComments, BLOCK_SIZE as FLAC_BLOCK_SIZE};
+pub use loudness::*;
+pub use resample::*;
+pub use trim::*;
+#[cfg(feature = "vorbis")]
+pub use vorbis::VorbisOptions;
+pub use wav::*;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::job::SpeechOutput;
+use crate::preset::VoicePreset;
+
+/// メタデータに埋め込むプリセットの値
+fn preset_summary(preset: &VoicePreset) -> String {
+    format!(
+        "volume={:.2} speed={:.2} pitch={:.2} range={:.2} pause_middle={} pause_long={} pause_sentence={} styles={}",
+        preset.volume,
+        preset.speed,
+        preset.pitch,
+        preset.range,
+        preset.pause_middle,
+        preset.pause_long,
+        preset.pause_sentence,
+        preset.styles,
+    )
+}
+
+/// サンプリング周波数付きの 16 bit モノラル PCM
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PcmBuffer {
+    sample_rate: u32,
+    samples: Vec<i16>,
+}
+
+impl PcmBuffer {
+    pub fn new(sample_rate: u32, samples: Vec<i16>) -> Self {
+        Self {
+            sample_rate,
+            samples,
+        }
+    }
+
+    /// `sample_rate` は `Aitalked::hz_voice_db`
+    pub fn from_output(sample_rate: u32, output: &SpeechOutput) -> Self {
+        Self::new(sample_rate, output.samples.clone())
+    }
+
+    /// `get_data` で受け取ったリトルエンディアンのバイト列から
+    pub fn from_le_bytes(sample_rate: u32, bytes: &[u8]) -> Self {
+        let samples = bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+
+        Self::new(sample_rate, samples)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn samples_mut(&mut self) -> &mut Vec<i16> {
+        &mut self.samples
+    }
+
+    pub fn into_samples(self) -> Vec<i16> {
+        self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn duration(&self) -> Duration {
+        match self.sample_rate {
+            0 => Duration::ZERO,
+            rate => Duration::from_secs_f64(self.samples.len() as f64 / rate as f64),
+        }
+    }
+
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        self.samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    /// `output_rate` に変換したもの
+    pub fn resample(&self, output_rate: u32, quality: ResampleQuality) -> Self {
+        Self::new(
+            output_rate,
+            Resampler::resample(&self.samples, self.sample_rate, output_rate, quality),
+        )
+    }
+
+    pub fn measure_loudness(&self) -> LoudnessMeter {
+        let mut meter = LoudnessMeter::new(self.sample_rate);
+        meter.push(&self.samples);
+        meter
+    }
+
+    /// 統合ラウドネスを揃え、トゥルーピークを制限したもの
+    pub fn normalize_loudness(&self, options: &LoudnessOptions) -> Self {
+        Self::new(
+            self.sample_rate,
+            normalize_loudness(&self.samples, self.sample_rate, options),
+        )
+    }
+
+    /// 前後の無音を除き、`options.lead` と `options.trail` の無音を付けたもの
+    pub fn trim_silence(&self, options: &TrimOptions) -> Self {
+        Self::new(
+            self.sample_rate,
+            trim_silence(&self.samples, self.sample_rate, options),
+        )
+    }
+
+    pub fn pad(&self, lead: Duration, trail: Duration) -> Self {
+        Self::new(
+            self.sample_rate,
+            pad(&self.samples, self.sample_rate, lead, trail),
+        )
+    }
+
+    /// 長さが分かっているので、シークできない出力にも正しいヘッダで書ける
+    pub fn write_wav<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.write_wav_with_metadata(writer, &WavMetadata::default())
+    }
+
+    pub fn write_wav_with_metadata<W: Write>(
+        &self,
+        mut writer: W,
+        metadata: &WavMetadata,
+    ) -> io::Result<()> {
+        let data_len = data_len(self.samples.len())?;
+        write_header(
+            &mut writer,
+            self.sample_rate,
+            data_len,
+            metadata.chunks_len(),
+        )?;
+        write_samples(&mut writer, &self.samples)?;
+        metadata.write_chunks(&mut writer)
+    }
+
+    pub fn to_wav_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(HEADER_LEN as usize + self.samples.len() * 2);
+        self.write_wav(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    pub fn save_wav(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.save_wav_with_metadata(path, &WavMetadata::default())
+    }
+
+    pub fn save_wav_with_metadata(
+        &self,
+        path: impl AsRef<Path>,
+        metadata: &WavMetadata,
+    ) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_wav_with_metadata(&mut writer, metadata)?;
+        writer.flush()
+    }
+
+    #[cfg(feature = "flac")]
+    pub fn write_flac<W: Write>(&self, mut writer: W, comments: &VorbisComments) -> io::Result<()> {
+        flac::write_flac(&mut writer, &self.samples, self.sample_rate, comments)
+    }
+
+    #[cfg(feature = "flac")]
+    pub fn save_flac(&self, path: impl AsRef<Path>, comments: &VorbisComments) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_flac(&mut writer, comments)?;
+        writer.flush()
+    }
+
+    /// Ogg に FLAC を格納する (拡張子は `.oga`)
+    #[cfg(feature = "ogg")]
+    pub fn write_ogg_flac<W: Write>(&self, writer: W, comments: &VorbisComments) -> io::Result<()> {
+        ogg::write_ogg_flac(writer, &self.samples, self.sample_rate, comments)
+    }
+
+    #[cfg(feature = "ogg")]
+    pub fn save_ogg_flac(
+        &self,
+        path: impl AsRef<Path>,
+        comments: &VorbisComments,
+    ) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_ogg_flac(&mut writer, comments)?;
+        writer.flush()
+    }
+
+    /// Ogg Vorbis で書き出す (拡張子は `.ogg`)
+    #[cfg(feature = "vorbis")]
+    pub fn write_ogg_vorbis<W: Write>(
+        &self,
+        writer: W,
+        comments: &VorbisComments,
+        options: &VorbisOptions,
+    ) -> io::Result<()> {
+        vorbis::write_ogg_vorbis(writer, &self.samples, self.sample_rate, comments, options)
+    }
+
+    #[cfg(feature = "vorbis")]
+    pub fn save_ogg_vorbis(
+        &self,
+        path: impl AsRef<Path>,
+        comments: &VorbisComments,
+        options: &VorbisOptions,
+    ) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_ogg_vorbis(&mut writer, comments, options)?;
+        writer.flush()
+    }
+}
@@ -0,0 +1,510 @@
+//! 16 bit モノラル PCM 用の FLAC エンコーダ
+//!
+//! 固定長ブロック・固定予測 (0〜4次) と Rice 符号だけを使う。MD5 は未設定 (全て 0) にする
+
+use std::io::{self, Write};
+
+use super::WavMetadata;
+use crate::preset::VoicePreset;
+
+pub const BLOCK_SIZE: usize = 4096;
+
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 8;
+const MAX_RICE_PARAMETER: u32 = 14;
+
+const SUBFRAME_CONSTANT: u64 = 0b000000;
+const SUBFRAME_VERBATIM: u64 = 0b000001;
+const SUBFRAME_FIXED: u64 = 0b001000;
+
+const METADATA_STREAMINFO: u8 = 0;
+const METADATA_VORBIS_COMMENT: u8 = 4;
+
+/// FLAC・Ogg に埋め込むタグ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VorbisComments {
+    pub vendor: String,
+    pub comments: Vec<(String, String)>,
+}
+
+impl Default for VorbisComments {
+    fn default() -> Self {
+        Self {
+            vendor: concat!("aitalked ", env!("CARGO_PKG_VERSION")).to_owned(),
+            comments: vec![],
+        }
+    }
+}
+
+impl VorbisComments {
+    pub const TEXT: &'static str = "DESCRIPTION";
+    pub const VOICE: &'static str = "ARTIST";
+    pub const PRESET: &'static str = "AITALKED_PRESET";
+    pub const ENGINE_VERSION: &'static str = "AITALKED_ENGINE";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 同じキー (大文字小文字を区別しない) があれば置き換える
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self
+            .comments
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        {
+            Some((_, v)) => *v = value,
+            None => self.comments.push((key.to_ascii_uppercase(), value)),
+        }
+    }
+
+    pub fn with(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.comments
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn with_text(self, text: impl Into<String>) -> Self {
+        self.with(Self::TEXT, text)
+    }
+
+    pub fn with_voice(self, voice: impl Into<String>) -> Self {
+        self.with(Self::VOICE, voice)
+    }
+
+    /// 話者名とプリセットの値を書き込む
+    pub fn with_preset(self, preset: &VoicePreset) -> Self {
+        self.with_voice(preset.voice_name.clone())
+            .with(Self::PRESET, super::preset_summary(preset))
+    }
+
+    pub fn with_engine_version(self, version: impl Into<String>) -> Self {
+        self.with(Self::ENGINE_VERSION, version)
+    }
+
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        bytes.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(self.vendor.as_bytes());
+        bytes.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+
+        for (key, value) in &self.comments {
+            let comment = format!("{key}={value}");
+            bytes.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(comment.as_bytes());
+        }
+
+        bytes
+    }
+}
+
+/// `INFO` の入力テキスト・話者名・プリセット・エンジンのバージョンを引き継ぐ
+impl From<&WavMetadata> for VorbisComments {
+    fn from(metadata: &WavMetadata) -> Self {
+        let mut comments = Self::new();
+
+        for (id, value) in &metadata.info {
+            let key = match *id {
+                WavMetadata::TEXT => Self::TEXT,
+                WavMetadata::VOICE => Self::VOICE,
+                WavMetadata::PRESET => Self::PRESET,
+                WavMetadata::SOFTWARE => Self::ENGINE_VERSION,
+                _ => continue,
+            };
+            comments.set(key, value.clone());
+        }
+
+        comments
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: vec![],
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    /// 上位ビットから `n` (32 以下) ビット
+    fn write(&mut self, value: u64, n: u32) {
+        debug_assert!(n <= 32);
+
+        self.buffer = (self.buffer << n) | (value & ((1 << n) - 1));
+        self.bits += n;
+
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.buffer >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i32, n: u32) {
+        self.write(value as u32 as u64, n);
+    }
+
+    /// `zeros` 個の 0 の後に 1
+    fn write_unary(&mut self, mut zeros: u32) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// フレームヘッダの `sample rate` (0 は STREAMINFO を参照)
+fn sample_rate_code(sample_rate: u32) -> u64 {
+    match sample_rate {
+        88200 => 0b0001,
+        176400 => 0b0010,
+        192000 => 0b0011,
+        8000 => 0b0100,
+        16000 => 0b0101,
+        22050 => 0b0110,
+        24000 => 0b0111,
+        32000 => 0b1000,
+        44100 => 0b1001,
+        48000 => 0b1010,
+        96000 => 0b1011,
+        _ => 0b0000,
+    }
+}
+
+/// フレーム番号の UTF-8 風の可変長符号 (36 bit まで)
+fn write_coded_number(writer: &mut BitWriter, n: u64) {
+    let (len, prefix) = match n {
+        0..0x80 => (1, 0x00),
+        0x80..0x800 => (2, 0xC0),
+        0x800..0x1_0000 => (3, 0xE0),
+        0x1_0000..0x20_0000 => (4, 0xF0),
+        0x20_0000..0x400_0000 => (5, 0xF8),
+        0x400_0000..0x8000_0000 => (6, 0xFC),
+        _ => (7, 0xFE),
+    };
+
+    writer.write(prefix | (n >> (6 * (len - 1))), 8);
+    for i in (0..len - 1).rev() {
+        writer.write(0x80 | ((n >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn fixed_residual(samples: &[i16], order: usize) -> Vec<i32> {
+    let x = |i: usize| samples[i] as i32;
+
+    (order..samples.len())
+        .map(|i| match order {
+            0 => x(i),
+            1 => x(i) - x(i - 1),
+            2 => x(i) - 2 * x(i - 1) + x(i - 2),
+            3 => x(i) - 3 * x(i - 1) + 3 * x(i - 2) - x(i - 3),
+            _ => x(i) - 4 * x(i - 1) + 6 * x(i - 2) - 4 * x(i - 3) + x(i - 4),
+        })
+        .collect()
+}
+
+fn zigzag(r: i32) -> u32 {
+    ((r << 1) ^ (r >> 31)) as u32
+}
+
+/// 分割ごとの Rice パラメータと、それを使ったときの概算ビット数
+fn rice_parameter(residual: &[i32]) -> (u32, u64) {
+    let n = residual.len() as u64;
+    let sum: u64 = residual.iter().map(|&r| zigzag(r) as u64).sum();
+
+    let estimate = |k: u32| 4 + n * (k as u64 + 1) + (sum >> k);
+
+    let mut k = 0;
+    while k < MAX_RICE_PARAMETER && n > 0 && (sum / n) >> (k + 1) > 0 {
+        k += 1;
+    }
+
+    let k = [k.saturating_sub(1), k, (k + 1).min(MAX_RICE_PARAMETER)]
+        .into_iter()
+        .min_by_key(|&k| estimate(k))
+        .unwrap();
+
+    (k, estimate(k))
+}
+
+/// 最も短くなる分割次数と各分割の Rice パラメータ
+fn partition(residual: &[i32], block_size: usize, order: usize) -> (u32, Vec<u32>, u64) {
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1usize << partition_order;
+        if !block_size.is_multiple_of(partitions) || block_size / partitions <= order {
+            break;
+        }
+
+        let len = block_size / partitions;
+        let mut parameters = vec![];
+        let mut bits = 6;
+        let mut start = 0;
+
+        for i in 0..partitions {
+            let end = start + if i == 0 { len - order } else { len };
+            let (k, estimate) = rice_parameter(&residual[start..end]);
+            parameters.push(k);
+            bits += estimate;
+            start = end;
+        }
+
+        if best.as_ref().is_none_or(|(_, _, b)| bits < *b) {
+            best = Some((partition_order, parameters, bits));
+        }
+    }
+
+    best.unwrap_or((0, vec![rice_parameter(residual).0], u64::MAX))
+}
+
+/// 固定予測の候補
+struct Fixed {
+    bits: u64,
+    order: usize,
+    residual: Vec<i32>,
+    partition_order: u32,
+    parameters: Vec<u32>,
+}
+
+/// 先頭のパディング・種類・wasted bits (無し)
+fn write_subframe_header(writer: &mut BitWriter, ty: u64) {
+    writer.write(0, 1);
+    writer.write(ty, 6);
+    writer.write(0, 1);
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i16]) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        write_subframe_header(writer, SUBFRAME_CONSTANT);
+        writer.write_signed(samples[0] as i32, BITS_PER_SAMPLE);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * BITS_PER_SAMPLE as u64;
+    let mut best: Option<Fixed> = None;
+
+    for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+        let residual = fixed_residual(samples, order);
+        let (partition_order, parameters, bits) = partition(&residual, samples.len(), order);
+        let bits = bits.saturating_add(order as u64 * BITS_PER_SAMPLE as u64);
+
+        if best.as_ref().is_none_or(|b| bits < b.bits) {
+            best = Some(Fixed {
+                bits,
+                order,
+                residual,
+                partition_order,
+                parameters,
+            });
+        }
+    }
+
+    let Some(Fixed {
+        bits,
+        order,
+        residual,
+        partition_order,
+        parameters,
+    }) = best
+    else {
+        return write_verbatim(writer, samples);
+    };
+
+    if bits >= verbatim_bits {
+        return write_verbatim(writer, samples);
+    }
+
+    write_subframe_header(writer, SUBFRAME_FIXED | order as u64);
+    for &sample in &samples[..order] {
+        writer.write_signed(sample as i32, BITS_PER_SAMPLE);
+    }
+
+    writer.write(0b00, 2);
+    writer.write(partition_order as u64, 4);
+
+    let len = samples.len() >> partition_order;
+    let mut start = 0;
+    for (i, &k) in parameters.iter().enumerate() {
+        let end = start + if i == 0 { len - order } else { len };
+        writer.write(k as u64, 4);
+        for &r in &residual[start..end] {
+            let u = zigzag(r);
+            writer.write_unary(u >> k);
+            writer.write(u as u64, k);
+        }
+        start = end;
+    }
+}
+
+fn write_verbatim(writer: &mut BitWriter, samples: &[i16]) {
+    write_subframe_header(writer, SUBFRAME_VERBATIM);
+    for &sample in samples {
+        writer.write_signed(sample as i32, BITS_PER_SAMPLE);
+    }
+}
+
+fn encode_frame(samples: &[i16], frame_number: u64, sample_rate: u32) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+
+    // 同期コード・固定長ブロック
+    writer.write(0xFFF8, 16);
+
+    let block_size_code = match samples.len() {
+        BLOCK_SIZE => 0b1100,
+        1..=256 => 0b0110,
+        _ => 0b0111,
+    };
+    writer.write(block_size_code, 4);
+    writer.write(sample_rate_code(sample_rate), 4);
+    // モノラル・16 bit
+    writer.write(0b0000, 4);
+    writer.write(0b100, 3);
+    writer.write(0, 1);
+
+    write_coded_number(&mut writer, frame_number);
+
+    match block_size_code {
+        0b0110 => writer.write(samples.len() as u64 - 1, 8),
+        0b0111 => writer.write(samples.len() as u64 - 1, 16),
+        _ => (),
+    }
+
+    let header = writer.bytes.clone();
+    writer.write(crc8(&header) as u64, 8);
+
+    write_subframe(&mut writer, samples);
+
+    let mut frame = writer.into_bytes();
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    frame
+}
+
+/// 先頭からのフレーム
+pub(super) fn encode_frames(samples: &[i16], sample_rate: u32) -> Vec<Vec<u8>> {
+    samples
+        .chunks(BLOCK_SIZE)
+        .enumerate()
+        .map(|(i, block)| encode_frame(block, i as u64, sample_rate))
+        .collect()
+}
+
+pub(super) fn check_sample_rate(sample_rate: u32) -> io::Result<()> {
+    if sample_rate == 0 || sample_rate >= 1 << 20 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("sample rate {sample_rate} cannot be stored in FLAC"),
+        ));
+    }
+    Ok(())
+}
+
+/// メタデータブロックのヘッダ付き
+pub(super) fn metadata_block(ty: u8, last: bool, body: &[u8]) -> Vec<u8> {
+    let mut block = vec![(last as u8) << 7 | ty];
+    block.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    block.extend_from_slice(body);
+    block
+}
+
+pub(super) fn stream_info(sample_rate: u32, samples: usize, frames: &[Vec<u8>]) -> Vec<u8> {
+    let min_frame = frames.iter().map(Vec::len).min().unwrap_or(0);
+    let max_frame = frames.iter().map(Vec::len).max().unwrap_or(0);
+
+    let mut writer = BitWriter::new();
+    writer.write(BLOCK_SIZE as u64, 16);
+    writer.write(BLOCK_SIZE as u64, 16);
+    writer.write(min_frame as u64, 24);
+    writer.write(max_frame as u64, 24);
+    writer.write(sample_rate as u64, 20);
+    writer.write(0, 3);
+    writer.write(BITS_PER_SAMPLE as u64 - 1, 5);
+    writer.write((samples as u64) >> 32, 4);
+    writer.write(samples as u64 & 0xFFFF_FFFF, 32);
+
+    let mut bytes = writer.into_bytes();
+    bytes.extend_from_slice(&[0; 16]);
+
+    metadata_block(METADATA_STREAMINFO, false, &bytes)
+}
+
+pub(super) fn vorbis_comment_block(comments: &VorbisComments) -> Vec<u8> {
+    metadata_block(METADATA_VORBIS_COMMENT, true, &comments.to_bytes())
+}
+
+pub(super) fn write_flac<W: Write>(
+    writer: &mut W,
+    samples: &[i16],
+    sample_rate: u32,
+    comments: &VorbisComments,
+) -> io::Result<()> {
+    check_sample_rate(sample_rate)?;
+
+    let frames = encode_frames(samples, sample_rate);
+
+    writer.write_all(b"fLaC")?;
+    writer.write_all(&stream_info(sample_rate, samples.len(), &frames))?;
+    writer.write_all(&vorbis_comment_block(comments))?;
+
+    for frame in frames {
+        writer.write_all(&frame)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,607 @@
+//! ラウドネスの測定 (ITU-R BS.1770 / EBU R128) と正規化
+//!
+//! 正規化は、統合ラウドネスが目標値になるよう一定のゲインをかけ、トゥルーピークが上限を超える所だけ先読みリミッタで抑える
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use super::resample::{dot, to_i16, Kernel, ResampleQuality};
+use crate::job::SpeechChunk;
+
+/// EBU R128 の目標値
+pub const EBU_R128_TARGET: f64 = -23.0;
+
+const FULL_SCALE: f64 = 32768.0;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+/// トゥルーピークを求める際のオーバーサンプリングの倍率
+const OVERSAMPLING: usize = 4;
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f64) -> f64 {
+    20.0 * gain.log10()
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// K 特性フィルタ (任意のサンプリング周波数向けに係数を求める)
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    // 高域シェルフ
+    let (f0, g, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / fs).tan();
+    let vh = db_to_gain(g);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    // 低域カット
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, highpass]
+}
+
+/// サンプルとその次のサンプルとの間を補間した値の絶対値の最大
+#[derive(Debug, Clone)]
+struct TruePeak {
+    kernel: Kernel,
+    window: VecDeque<f32>,
+}
+
+impl TruePeak {
+    fn new() -> Self {
+        let kernel = Kernel::new(ResampleQuality::Balanced, 1.0, OVERSAMPLING, false);
+        let window = VecDeque::from(vec![0.0; kernel.half - 1]);
+        Self { kernel, window }
+    }
+
+    /// 結果は `delay` サンプル前のもの
+    fn delay(&self) -> usize {
+        self.kernel.half
+    }
+
+    fn push(&mut self, sample: f32) -> Option<f32> {
+        self.window.push_back(sample);
+        if self.window.len() < self.kernel.taps() {
+            return None;
+        }
+
+        // 最初の位相はサンプルそのもの
+        let window = self.window.make_contiguous();
+        let peak = (0..OVERSAMPLING)
+            .map(|phase| dot(self.kernel.row(phase), window).abs())
+            .fold(0.0, f32::max);
+
+        self.window.pop_front();
+        Some(peak)
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+        self.window.resize(self.kernel.half - 1, 0.0);
+    }
+}
+
+/// 統合ラウドネスとトゥルーピークを少しずつ測る
+#[derive(Debug, Clone)]
+pub struct LoudnessMeter {
+    sample_rate: u32,
+    filters: [Biquad; 2],
+    /// 100 ms ごとの K 特性をかけた二乗和
+    step_len: usize,
+    steps: VecDeque<f64>,
+    step_sum: f64,
+    step_samples: usize,
+    /// 400 ms のブロック (75% ずつ重なる) ごとの平均二乗
+    blocks: Vec<f64>,
+    samples: u64,
+    sum: f64,
+    peak: TruePeak,
+    max_peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            filters: k_weighting(sample_rate),
+            step_len: (sample_rate as usize / 10).max(1),
+            steps: VecDeque::with_capacity(4),
+            step_sum: 0.0,
+            step_samples: 0,
+            blocks: vec![],
+            samples: 0,
+            sum: 0.0,
+            peak: TruePeak::new(),
+            max_peak: 0.0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn push(&mut self, samples: &[i16]) {
+        for &sample in samples {
+            let x = sample as f64 / FULL_SCALE;
+            let y = self.filters.iter_mut().fold(x, |x, f| f.process(x));
+
+            self.step_sum += y * y;
+            self.step_samples += 1;
+            self.sum += y * y;
+            self.samples += 1;
+
+            if self.step_samples == self.step_len {
+                if self.steps.len() == 4 {
+                    self.steps.pop_front();
+                }
+                self.steps.push_back(self.step_sum);
+                if self.steps.len() == 4 {
+                    let sum: f64 = self.steps.iter().sum();
+                    self.blocks.push(sum / (4 * self.step_len) as f64);
+                }
+                self.step_sum = 0.0;
+                self.step_samples = 0;
+            }
+
+            if let Some(peak) = self.peak.push(sample as f32) {
+                self.max_peak = self.max_peak.max(peak);
+            }
+        }
+    }
+
+    /// 統合ラウドネス (LUFS)。無音の場合は `None`
+    ///
+    /// 400 ms に満たない音声は全体を1つのブロックとして扱う
+    pub fn integrated(&self) -> Option<f64> {
+        let short;
+        let blocks = match self.blocks.is_empty() {
+            true if self.samples > 0 => {
+                short = [self.sum / self.samples as f64];
+                &short[..]
+            }
+            _ => &self.blocks[..],
+        };
+
+        let gated = |threshold: f64| {
+            let (sum, count) = blocks
+                .iter()
+                .filter(|&&e| energy_to_lufs(e) > threshold)
+                .fold((0.0, 0), |(sum, count), e| (sum + e, count + 1));
+            (count > 0).then(|| sum / count as f64)
+        };
+
+        let relative = energy_to_lufs(gated(ABSOLUTE_GATE)?) + RELATIVE_GATE;
+        gated(relative.max(ABSOLUTE_GATE)).map(energy_to_lufs)
+    }
+
+    /// トゥルーピーク (dBTP)。無音の場合は `f64::NEG_INFINITY`
+    pub fn true_peak(&self) -> f64 {
+        let mut peak = self.peak.clone();
+        let max = (0..peak.delay())
+            .filter_map(|_| peak.push(0.0))
+            .fold(self.max_peak, f32::max);
+        gain_to_db(max as f64 / FULL_SCALE)
+    }
+
+    /// 統合ラウドネスを `target` (LUFS) にするためのゲイン (dB)
+    pub fn gain_to(&self, target: f64) -> f64 {
+        self.integrated().map_or(0.0, |l| target - l)
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.sample_rate);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoudnessOptions {
+    /// 目標の統合ラウドネス (LUFS)
+    pub target: f64,
+    /// トゥルーピークの上限 (dBTP)
+    pub true_peak: f64,
+    /// リミッタの先読みの長さ (ゲインを下げ始めてから上限に達するまで)
+    pub lookahead: Duration,
+    /// リミッタがゲインを戻す時定数
+    pub release: Duration,
+}
+
+impl Default for LoudnessOptions {
+    fn default() -> Self {
+        Self {
+            target: EBU_R128_TARGET,
+            true_peak: -1.0,
+            lookahead: Duration::from_millis(5),
+            release: Duration::from_millis(100),
+        }
+    }
+}
+
+impl LoudnessOptions {
+    pub fn with_target(mut self, target: f64) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn with_true_peak(mut self, true_peak: f64) -> Self {
+        self.true_peak = true_peak;
+        self
+    }
+}
+
+/// ゲインをかけ、トゥルーピークが上限を超えないよう抑える
+///
+/// 出力は `latency` サンプル遅れる。全体を通した出力の長さは入力と同じ
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    ceiling: f32,
+    /// 先読みのサンプル数
+    lookahead: usize,
+    release: f32,
+    gain: f32,
+    target_gain: f32,
+    /// ゲインを目標に近づける割合 (1サンプルあたり)
+    smoothing: f32,
+    peak: TruePeak,
+    /// 出力待ちの入力
+    delayed: VecDeque<f32>,
+    /// (位置, 必要なゲイン) の、ゲインが単調増加する列
+    minimums: VecDeque<(u64, f32)>,
+    position: u64,
+    released: f32,
+    /// 直近 `lookahead` 個の、リリースをかけたゲイン
+    recent: VecDeque<f32>,
+    recent_sum: f64,
+    input_len: u64,
+    output_len: u64,
+}
+
+impl Limiter {
+    /// `ceiling` は dBTP
+    pub fn new(sample_rate: u32, ceiling: f64, lookahead: Duration, release: Duration) -> Self {
+        let lookahead = ((lookahead.as_secs_f64() * sample_rate as f64).round() as usize).max(1);
+        let release = match release.as_secs_f64() * sample_rate as f64 {
+            samples if samples > 0.0 => (-1.0 / samples).exp() as f32,
+            _ => 0.0,
+        };
+
+        let mut limiter = Self {
+            ceiling: (db_to_gain(ceiling) * FULL_SCALE) as f32,
+            lookahead,
+            release,
+            gain: 1.0,
+            target_gain: 1.0,
+            smoothing: (1.0 / (0.05 * sample_rate as f64)).min(1.0) as f32,
+            peak: TruePeak::new(),
+            delayed: VecDeque::new(),
+            minimums: VecDeque::new(),
+            position: 0,
+            released: 1.0,
+            recent: VecDeque::new(),
+            recent_sum: 0.0,
+            input_len: 0,
+            output_len: 0,
+        };
+        limiter.reset();
+        limiter
+    }
+
+    pub fn from_options(sample_rate: u32, options: &LoudnessOptions) -> Self {
+        Self::new(
+            sample_rate,
+            options.true_peak,
+            options.lookahead,
+            options.release,
+        )
+    }
+
+    pub fn latency(&self) -> usize {
+        self.peak.delay() + self.lookahead - 1
+    }
+
+    /// 入力にかけるゲイン (dB) をすぐに変える
+    pub fn set_gain(&mut self, db: f64) {
+        self.target_gain = db_to_gain(db) as f32;
+        self.gain = self.target_gain;
+    }
+
+    /// 入力にかけるゲイン (dB) を 50 ms 程度かけて変える
+    pub fn ramp_gain(&mut self, db: f64) {
+        self.target_gain = db_to_gain(db) as f32;
+    }
+
+    pub fn process_into(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        self.input_len += input.len() as u64;
+        for &sample in input {
+            self.gain += (self.target_gain - self.gain) * self.smoothing;
+            self.push(sample as f32 * self.gain, output);
+        }
+    }
+
+    pub fn process(&mut self, input: &[i16]) -> Vec<i16> {
+        let mut output = Vec::with_capacity(input.len());
+        self.process_into(input, &mut output);
+        output
+    }
+
+    /// 遅れている分を出力し、次の入力を受け付けられる状態に戻す
+    pub fn finish_into(&mut self, output: &mut Vec<i16>) {
+        let end = output.len() + (self.input_len - self.output_len) as usize;
+        for _ in 0..self.latency() {
+            self.push(0.0, output);
+        }
+        output.truncate(end);
+        self.reset();
+    }
+
+    pub fn finish(&mut self) -> Vec<i16> {
+        let mut output = vec![];
+        self.finish_into(&mut output);
+        output
+    }
+
+    pub fn reset(&mut self) {
+        self.peak.reset();
+        self.delayed.clear();
+        self.minimums.clear();
+        self.position = 0;
+        self.released = 1.0;
+        self.recent.clear();
+        self.recent_sum = 0.0;
+        self.input_len = 0;
+        self.output_len = 0;
+
+        // ゲインの平均を取る窓を埋めるため、先頭に無音があるものとして扱う
+        for _ in 1..self.lookahead {
+            self.push(0.0, &mut vec![]);
+        }
+    }
+
+    fn push(&mut self, sample: f32, output: &mut Vec<i16>) {
+        self.delayed.push_back(sample);
+
+        let Some(peak) = self.peak.push(sample) else {
+            return;
+        };
+        let required = match peak > self.ceiling {
+            true => self.ceiling / peak,
+            false => 1.0,
+        };
+
+        // 先読みの範囲の最小値
+        let index = self.position;
+        self.position += 1;
+        while self.minimums.back().is_some_and(|&(_, g)| g >= required) {
+            self.minimums.pop_back();
+        }
+        self.minimums.push_back((index, required));
+
+        let Some(start) = (index + 1).checked_sub(self.lookahead as u64) else {
+            return;
+        };
+        while self.minimums.front().is_some_and(|&(i, _)| i < start) {
+            self.minimums.pop_front();
+        }
+        let minimum = self.minimums.front().map_or(1.0, |&(_, g)| g);
+
+        self.released = minimum.min(1.0 - (1.0 - self.released) * self.release);
+
+        // 先読みの長さで平均して、上限に達するまで滑らかに下げる
+        self.recent.push_back(self.released);
+        self.recent_sum += self.released as f64;
+        if self.recent.len() > self.lookahead {
+            self.recent_sum -= self.recent.pop_front().unwrap() as f64;
+        }
+        let sample = self.delayed.pop_front().unwrap();
+        if self.recent.len() < self.lookahead {
+            // 先頭に足した無音
+            return;
+        }
+        let gain = (self.recent_sum / self.lookahead as f64) as f32;
+
+        output.push(to_i16((sample * gain).clamp(-self.ceiling, self.ceiling)));
+        self.output_len += 1;
+    }
+}
+
+/// 統合ラウドネスを `options.target` にし、トゥルーピークを `options.true_peak` 以下にする
+pub fn normalize_loudness(
+    samples: &[i16],
+    sample_rate: u32,
+    options: &LoudnessOptions,
+) -> Vec<i16> {
+    let mut meter = LoudnessMeter::new(sample_rate);
+    meter.push(samples);
+
+    let mut limiter = Limiter::from_options(sample_rate, options);
+    limiter.set_gain(meter.gain_to(options.target));
+
+    let mut output = Vec::with_capacity(samples.len());
+    limiter.process_into(samples, &mut output);
+    limiter.finish_into(&mut output);
+    output
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoudnessMode {
+    /// 音声を最後まで溜めて測ってから返す
+    TwoPass,
+    /// 指定した長さだけ遅らせ、それまでに測ったラウドネスでゲインを決める
+    Lookahead(Duration),
+}
+
+/// `SpeechStream` の音声のラウドネスを揃えながら返す
+///
+/// イベントは音声との順序を保ったまま通す
+pub struct LoudnessStream<I> {
+    inner: I,
+    mode: LoudnessMode,
+    target: f64,
+    meter: LoudnessMeter,
+    limiter: Limiter,
+    pending: VecDeque<SpeechChunk>,
+    /// `pending` 中の音声のサンプル数
+    pending_samples: usize,
+    window: usize,
+    /// ゲインを一度決めたか
+    measured: bool,
+    output: VecDeque<SpeechChunk>,
+    tick: u64,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = SpeechChunk>> LoudnessStream<I> {
+    /// `sample_rate` は `Aitalked::hz_voice_db`
+    pub fn new(inner: I, sample_rate: u32, mode: LoudnessMode, options: LoudnessOptions) -> Self {
+        let window = match mode {
+            LoudnessMode::TwoPass => usize::MAX,
+            LoudnessMode::Lookahead(duration) => {
+                (duration.as_secs_f64() * sample_rate as f64) as usize
+            }
+        };
+
+        Self {
+            inner,
+            mode,
+            target: options.target,
+            meter: LoudnessMeter::new(sample_rate),
+            limiter: Limiter::from_options(sample_rate, &options),
+            pending: VecDeque::new(),
+            pending_samples: 0,
+            window,
+            measured: false,
+            output: VecDeque::new(),
+            tick: 0,
+            finished: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &I {
+        &self.inner
+    }
+
+    pub fn mode(&self) -> LoudnessMode {
+        self.mode
+    }
+
+    /// ここまでに受け取った音声の測定結果
+    pub fn meter(&self) -> &LoudnessMeter {
+        &self.meter
+    }
+
+    fn update_gain(&mut self) {
+        let gain = self.meter.gain_to(self.target);
+        match self.measured {
+            true => self.limiter.ramp_gain(gain),
+            false => self.limiter.set_gain(gain),
+        }
+        self.measured = true;
+    }
+
+    /// `pending` の先頭から、`keep` サンプルより後ろの音声がある間だけ出力に回す
+    fn release(&mut self, keep: usize) {
+        while let Some(chunk) = self.pending.front() {
+            let len = match chunk {
+                SpeechChunk::Audio { samples, .. } => samples.len(),
+                SpeechChunk::Event(_) => 0,
+            };
+            if len > 0 && self.pending_samples - len < keep {
+                break;
+            }
+
+            self.pending_samples -= len;
+            match self.pending.pop_front().unwrap() {
+                SpeechChunk::Audio { tick, samples } => {
+                    self.tick = tick;
+                    let samples = self.limiter.process(&samples);
+                    if !samples.is_empty() {
+                        self.output.push_back(SpeechChunk::Audio { tick, samples });
+                    }
+                }
+                event => self.output.push_back(event),
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = SpeechChunk>> Iterator for LoudnessStream<I> {
+    type Item = SpeechChunk;
+
+    fn next(&mut self) -> Option<SpeechChunk> {
+        loop {
+            if let Some(chunk) = self.output.pop_front() {
+                return Some(chunk);
+            }
+
+            if self.finished {
+                return None;
+            }
+
+            match self.inner.next() {
+                Some(chunk) => {
+                    if let SpeechChunk::Audio { samples, .. } = &chunk {
+                        self.meter.push(samples);
+                        self.pending_samples += samples.len();
+                    }
+                    self.pending.push_back(chunk);
+
+                    if self.mode != LoudnessMode::TwoPass && self.pending_samples >= self.window {
+                        self.update_gain();
+                    }
+                    self.release(self.window);
+                }
+                None => {
+                    self.finished = true;
+                    self.update_gain();
+                    self.release(0);
+
+                    let samples = self.limiter.finish();
+                    if !samples.is_empty() {
+                        self.output.push_back(SpeechChunk::Audio {
+                            tick: self.tick,
+                            samples,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! FLAC を Ogg に格納する (Ogg FLAC 1.0 マッピング)
+
+use std::io::{self, Write};
+
+use ogg::{PacketWriteEndInfo, PacketWriter};
+
+use super::flac::{self, VorbisComments};
+
+pub(super) const SERIAL: u32 = u32::from_be_bytes(*b"AITK");
+
+pub(super) fn write_ogg_flac<W: Write>(
+    writer: W,
+    samples: &[i16],
+    sample_rate: u32,
+    comments: &VorbisComments,
+) -> io::Result<()> {
+    flac::check_sample_rate(sample_rate)?;
+
+    let frames = flac::encode_frames(samples, sample_rate);
+
+    // 最初のパケット: マッピングのヘッダ・ヘッダパケット数・STREAMINFO
+    let mut first = vec![0x7F];
+    first.extend_from_slice(b"FLAC");
+    first.extend_from_slice(&[1, 0]);
+    first.extend_from_slice(&1u16.to_be_bytes());
+    first.extend_from_slice(b"fLaC");
+    first.extend_from_slice(&flac::stream_info(sample_rate, samples.len(), &frames));
+
+    let mut writer = PacketWriter::new(writer);
+
+    let count = frames.len();
+
+    // 音声が無ければヘッダパケットでストリームを終える
+    let last_header = match count {
+        0 => PacketWriteEndInfo::EndStream,
+        _ => PacketWriteEndInfo::EndPage,
+    };
+
+    writer.write_packet(first.into(), SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+    writer.write_packet(
+        flac::vorbis_comment_block(comments).into(),
+        SERIAL,
+        last_header,
+        0,
+    )?;
+
+    let mut granule = 0;
+
+    for (i, (frame, block)) in frames
+        .into_iter()
+        .zip(samples.chunks(flac::BLOCK_SIZE))
+        .enumerate()
+    {
+        granule += block.len() as u64;
+        let end = if i + 1 == count {
+            PacketWriteEndInfo::EndStream
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        writer.write_packet(frame.into(), SERIAL, end, granule)?;
+    }
+
+    writer.into_inner().flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use ogg::PacketReader;
+
+    use super::*;
+
+    fn packets(bytes: Vec<u8>) -> Vec<ogg::Packet> {
+        let mut reader = PacketReader::new(Cursor::new(bytes));
+        std::iter::from_fn(|| reader.read_packet().unwrap()).collect()
+    }
+
+    #[test]
+    fn empty_input_ends_on_header() {
+        let mut bytes = vec![];
+        write_ogg_flac(&mut bytes, &[], 44100, &VorbisComments::new()).unwrap();
+
+        let packets = packets(bytes);
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|p| !p.data.is_empty()));
+        assert!(packets[1].last_in_stream());
+    }
+
+    #[test]
+    fn granule_is_sample_count() {
+        let samples: Vec<i16> = (0..10000).map(|i| (i % 100) as i16).collect();
+        let mut bytes = vec![];
+        write_ogg_flac(&mut bytes, &samples, 44100, &VorbisComments::new()).unwrap();
+
+        let packets = packets(bytes);
+        let last = packets.last().unwrap();
+        assert!(last.last_in_stream());
+        assert_eq!(last.absgp_page(), 10000);
+    }
+}
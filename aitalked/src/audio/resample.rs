@@ -0,0 +1,362 @@
+//! サンプリング周波数の変換
+//!
+//! カイザー窓をかけた sinc 関数による帯域制限補間。フィルタは左右対称なので遅延はなく、
+//! 出力の先頭は入力の先頭と同じ時刻になる (tick はそのまま使える)
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::job::SpeechChunk;
+
+/// 位相をこれより細かく分ける必要がある比率では、隣り合う位相の係数を線形補間する
+const MAX_PHASES: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResampleQuality {
+    /// 短いフィルタ。リアルタイム処理や電話向けの帯域への変換に
+    Fast,
+    #[default]
+    Balanced,
+    /// 長いフィルタ。書き出し向け
+    High,
+}
+
+impl ResampleQuality {
+    /// (sinc の片側の零点の数, カイザー窓の β, 通過帯域の割合)
+    fn parameters(self) -> (usize, f64, f64) {
+        match self {
+            Self::Fast => (8, 6.0, 0.90),
+            Self::Balanced => (16, 8.6, 0.94),
+            Self::High => (32, 10.0, 0.97),
+        }
+    }
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// 第1種変形ベッセル関数 (0次)
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..64 {
+        term *= half / k as f64;
+        let t = term * term;
+        sum += t;
+        if t < sum * 1e-17 {
+            break;
+        }
+    }
+    sum
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Kernel {
+    /// 片側のタップ数
+    pub(super) half: usize,
+    /// 位相の数 (補間する場合は両端を含めて +1 個持つ)
+    phases: usize,
+    interpolate: bool,
+    /// 位相ごとに `2 * half` 個
+    table: Vec<f32>,
+}
+
+impl Kernel {
+    /// `cutoff` は入力のナイキスト周波数に対する割合
+    pub(super) fn new(
+        quality: ResampleQuality,
+        cutoff: f64,
+        phases: usize,
+        interpolate: bool,
+    ) -> Self {
+        let (zeros, beta, _) = quality.parameters();
+        let half = (zeros as f64 / cutoff).ceil() as usize;
+        let width = half as f64;
+        let i0_beta = bessel_i0(beta);
+        let rows = if interpolate { phases + 1 } else { phases };
+
+        let mut table = Vec::with_capacity(rows * 2 * half);
+        for phase in 0..rows {
+            let frac = phase as f64 / phases as f64;
+            for k in 0..2 * half {
+                // 入力 `floor(t) - half + 1 + k` と出力時刻 t との距離
+                let x = (k as f64 - half as f64 + 1.0) - frac;
+                let sinc = match x * cutoff {
+                    0.0 => 1.0,
+                    y => (PI * y).sin() / (PI * y),
+                };
+                let r = x / width;
+                let window = match r.abs() < 1.0 {
+                    true => bessel_i0(beta * (1.0 - r * r).sqrt()) / i0_beta,
+                    false => 0.0,
+                };
+                table.push((cutoff * sinc * window) as f32);
+            }
+        }
+
+        Self {
+            half,
+            phases,
+            interpolate,
+            table,
+        }
+    }
+
+    pub(super) fn taps(&self) -> usize {
+        2 * self.half
+    }
+
+    pub(super) fn row(&self, phase: usize) -> &[f32] {
+        let taps = self.taps();
+        &self.table[phase * taps..(phase + 1) * taps]
+    }
+
+    /// `frac` / `den` は出力時刻の小数部分
+    fn apply(&self, input: &[f32], frac: u64, den: u64) -> f32 {
+        if !self.interpolate {
+            return dot(self.row(frac as usize), input);
+        }
+
+        let position = frac as f64 * self.phases as f64 / den as f64;
+        let phase = position as usize;
+        let t = (position - phase as f64) as f32;
+        let a = dot(self.row(phase), input);
+        let b = dot(self.row(phase + 1), input);
+        a + (b - a) * t
+    }
+}
+
+pub(super) fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+pub(super) fn to_i16(sample: f32) -> i16 {
+    sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// 入力を少しずつ渡して変換する
+///
+/// 全体を通して `ceil(入力サンプル数 * 出力周波数 / 入力周波数)` サンプルを出力する
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    input_rate: u32,
+    output_rate: u32,
+    /// 出力1サンプルあたりに進む入力サンプル数は `step / den`
+    step: u64,
+    den: u64,
+    kernel: Option<Kernel>,
+    /// 出力時刻より前・後に必要な入力サンプル数
+    behind: usize,
+    ahead: usize,
+    /// 先頭に `behind` サンプル分の無音を置いた入力の残り
+    history: Vec<f32>,
+    /// 次の出力時刻の整数部分 (`history` 上の位置)
+    next: usize,
+    frac: u64,
+    input_len: u64,
+    output_len: u64,
+}
+
+impl Resampler {
+    /// 周波数が 0 の場合は panic
+    pub fn new(input_rate: u32, output_rate: u32, quality: ResampleQuality) -> Self {
+        assert!(
+            input_rate > 0 && output_rate > 0,
+            "sample rate must be non-zero"
+        );
+
+        let g = gcd(input_rate as u64, output_rate as u64);
+        let (step, den) = (input_rate as u64 / g, output_rate as u64 / g);
+
+        let kernel = (input_rate != output_rate).then(|| {
+            let (_, _, passband) = quality.parameters();
+            let cutoff = (output_rate as f64 / input_rate as f64).min(1.0) * passband;
+            match den <= MAX_PHASES {
+                true => Kernel::new(quality, cutoff, den as usize, false),
+                false => Kernel::new(quality, cutoff, MAX_PHASES as usize, true),
+            }
+        });
+        let (behind, ahead) = kernel.as_ref().map_or((0, 0), |k| (k.half - 1, k.half));
+
+        Self {
+            input_rate,
+            output_rate,
+            step,
+            den,
+            kernel,
+            behind,
+            ahead,
+            history: vec![0.0; behind],
+            next: behind,
+            frac: 0,
+            input_len: 0,
+            output_len: 0,
+        }
+    }
+
+    pub fn input_rate(&self) -> u32 {
+        self.input_rate
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// 入力の長さに対する出力の長さ
+    pub fn output_len(&self, input_len: usize) -> usize {
+        (input_len as u64 * self.den).div_ceil(self.step) as usize
+    }
+
+    /// 変換できた分だけ `output` に追加する
+    pub fn process_into(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        self.input_len += input.len() as u64;
+        self.history.extend(input.iter().map(|&s| s as f32));
+        self.run(output, u64::MAX);
+    }
+
+    pub fn process(&mut self, input: &[i16]) -> Vec<i16> {
+        let mut output = Vec::with_capacity(self.output_len(input.len()) + 1);
+        self.process_into(input, &mut output);
+        output
+    }
+
+    /// 残りを出力し、次の入力を受け付けられる状態に戻す
+    pub fn finish_into(&mut self, output: &mut Vec<i16>) {
+        let total = (self.input_len * self.den).div_ceil(self.step);
+
+        self.history
+            .extend(std::iter::repeat_n(0.0, self.ahead + 1));
+        self.run(output, total);
+        self.reset();
+    }
+
+    pub fn finish(&mut self) -> Vec<i16> {
+        let mut output = vec![];
+        self.finish_into(&mut output);
+        output
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.history.resize(self.behind, 0.0);
+        self.next = self.behind;
+        self.frac = 0;
+        self.input_len = 0;
+        self.output_len = 0;
+    }
+
+    /// 全体を一度に変換する
+    pub fn resample(
+        samples: &[i16],
+        input_rate: u32,
+        output_rate: u32,
+        quality: ResampleQuality,
+    ) -> Vec<i16> {
+        let mut resampler = Self::new(input_rate, output_rate, quality);
+        let mut output = Vec::with_capacity(resampler.output_len(samples.len()));
+        resampler.process_into(samples, &mut output);
+        resampler.finish_into(&mut output);
+        output
+    }
+
+    fn run(&mut self, output: &mut Vec<i16>, limit: u64) {
+        while self.next + self.ahead < self.history.len() && self.output_len < limit {
+            let sample = match &self.kernel {
+                Some(kernel) => {
+                    let start = self.next - self.behind;
+                    kernel.apply(
+                        &self.history[start..start + kernel.taps()],
+                        self.frac,
+                        self.den,
+                    )
+                }
+                None => self.history[self.next],
+            };
+            output.push(to_i16(sample));
+            self.output_len += 1;
+
+            self.frac += self.step;
+            self.next += (self.frac / self.den) as usize;
+            self.frac %= self.den;
+        }
+
+        // 次の出力に必要な分だけ残す
+        let consumed = (self.next - self.behind).min(self.history.len());
+        self.history.drain(..consumed);
+        self.next -= consumed;
+    }
+}
+
+/// `SpeechStream` の音声を変換しながら返す
+///
+/// イベントはそのまま通す。フィルタの長さの分だけ音声が遅れて届くので、最後に残りを1つの `Audio` として返す
+pub struct ResampleStream<I> {
+    inner: I,
+    resampler: Resampler,
+    pending: VecDeque<SpeechChunk>,
+    tick: u64,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = SpeechChunk>> ResampleStream<I> {
+    /// `input_rate` は `Aitalked::hz_voice_db`
+    pub fn new(inner: I, input_rate: u32, output_rate: u32, quality: ResampleQuality) -> Self {
+        Self {
+            inner,
+            resampler: Resampler::new(input_rate, output_rate, quality),
+            pending: VecDeque::new(),
+            tick: 0,
+            finished: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &I {
+        &self.inner
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.resampler.output_rate()
+    }
+}
+
+impl<I: Iterator<Item = SpeechChunk>> Iterator for ResampleStream<I> {
+    type Item = SpeechChunk;
+
+    fn next(&mut self) -> Option<SpeechChunk> {
+        loop {
+            if let Some(chunk) = self.pending.pop_front() {
+                return Some(chunk);
+            }
+
+            if self.finished {
+                return None;
+            }
+
+            match self.inner.next() {
+                Some(SpeechChunk::Audio { tick, samples }) => {
+                    self.tick = tick;
+                    let samples = self.resampler.process(&samples);
+                    if !samples.is_empty() {
+                        self.pending.push_back(SpeechChunk::Audio { tick, samples });
+                    }
+                }
+                Some(chunk) => self.pending.push_back(chunk),
+                None => {
+                    self.finished = true;
+                    let samples = self.resampler.finish();
+                    if !samples.is_empty() {
+                        self.pending.push_back(SpeechChunk::Audio {
+                            tick: self.tick,
+                            samples,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! 前後の無音の除去と、決まった長さの無音の付加
+//!
+//! エンジンが出す前後の無音 (`pause_begin` / `pause_term`) には小さなノイズが残ることがあるので、
+//! しきい値で声の範囲を求めて切り出し、ミリ秒単位で指定した長さの完全な無音を付け直す
+
+use std::ops::Range;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrimOptions {
+    /// これ以下を無音とみなす (dBFS)
+    pub threshold: f64,
+    /// RMS を求める区間の長さ
+    pub window: Duration,
+    /// 声の範囲の外側に残す長さ。この部分でフェードイン・フェードアウトする
+    pub margin: Duration,
+    /// 先頭に付ける無音
+    pub lead: Duration,
+    /// 末尾に付ける無音
+    pub trail: Duration,
+}
+
+impl Default for TrimOptions {
+    fn default() -> Self {
+        Self {
+            threshold: -50.0,
+            window: Duration::from_millis(10),
+            margin: Duration::from_millis(10),
+            lead: Duration::ZERO,
+            trail: Duration::ZERO,
+        }
+    }
+}
+
+impl TrimOptions {
+    pub fn with_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn with_padding(mut self, lead: Duration, trail: Duration) -> Self {
+        self.lead = lead;
+        self.trail = trail;
+        self
+    }
+}
+
+/// 時間をサンプル数に (最も近い値に丸める)
+pub fn duration_to_samples(duration: Duration, sample_rate: u32) -> usize {
+    ((duration.as_nanos() * sample_rate as u128 + 500_000_000) / 1_000_000_000) as usize
+}
+
+/// しきい値を超える最初のサンプルから最後のサンプルまで
+fn voice_range(samples: &[i16], sample_rate: u32, options: &TrimOptions) -> Option<Range<usize>> {
+    let level = 32768.0 * 10f64.powf(options.threshold / 20.0);
+    let window = duration_to_samples(options.window, sample_rate).max(1);
+
+    let loud = |frame: &&[i16]| {
+        let sum: f64 = frame.iter().map(|&s| (s as f64) * (s as f64)).sum();
+        (sum / frame.len() as f64).sqrt() > level
+    };
+    let above = |s: &i16| (*s as f64).abs() > level;
+
+    let frames: Vec<_> = samples.chunks(window).collect();
+    let first = frames.iter().position(loud)?;
+    let last = frames.iter().rposition(loud)?;
+
+    // 区間の中でしきい値を超える最初・最後のサンプルまで詰める
+    let start = first * window + frames[first].iter().position(above).unwrap_or(0);
+    let end = last * window
+        + frames[last]
+            .iter()
+            .rposition(above)
+            .map_or(frames[last].len(), |i| i + 1);
+
+    Some(start..end)
+}
+
+/// 声の範囲 (`margin` を含む)。すべて無音の場合は空の範囲
+///
+/// 切り出した後のイベントの位置は `lead - trim_range(..).start` サンプルずれる
+pub fn trim_range(samples: &[i16], sample_rate: u32, options: &TrimOptions) -> Range<usize> {
+    let margin = duration_to_samples(options.margin, sample_rate);
+    match voice_range(samples, sample_rate, options) {
+        Some(voice) => voice.start.saturating_sub(margin)..(voice.end + margin).min(samples.len()),
+        None => 0..0,
+    }
+}
+
+/// 無音を除いて `lead` と `trail` を付けたもの
+pub fn trim_silence(samples: &[i16], sample_rate: u32, options: &TrimOptions) -> Vec<i16> {
+    let Some(voice) = voice_range(samples, sample_rate, options) else {
+        return pad(&[], sample_rate, options.lead, options.trail);
+    };
+    let range = trim_range(samples, sample_rate, options);
+
+    let mut body = samples[range.clone()].to_vec();
+    let len = body.len();
+
+    let fade_in = voice.start - range.start;
+    for (i, sample) in body[..fade_in].iter_mut().enumerate() {
+        *sample = (*sample as f64 * i as f64 / fade_in as f64).round() as i16;
+    }
+    let fade_out = range.end - voice.end;
+    for (i, sample) in body[len - fade_out..].iter_mut().rev().enumerate() {
+        *sample = (*sample as f64 * i as f64 / fade_out as f64).round() as i16;
+    }
+
+    pad(&body, sample_rate, options.lead, options.trail)
+}
+
+/// 前後に無音を付けたもの
+pub fn pad(samples: &[i16], sample_rate: u32, lead: Duration, trail: Duration) -> Vec<i16> {
+    let lead = duration_to_samples(lead, sample_rate);
+    let trail = duration_to_samples(trail, sample_rate);
+
+    let mut output = Vec::with_capacity(lead + samples.len() + trail);
+    output.resize(lead, 0);
+    output.extend_from_slice(samples);
+    output.resize(output.len() + trail, 0);
+    output
+}
@@ -0,0 +1,1263 @@
+//! Ogg Vorbis エンコーダ (Vorbis I)
+//!
+//! 長短2種類のブロック・floor 1・residue 1・格子 VQ の符号帳だけを使う。
+//! `psy` で求めた許容ノイズから各係数の量子化の幅を決め、それを floor として送り、floor で割った残差を整数に丸めて符号化する
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::f64::consts::PI;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use ogg::{PacketWriteEndInfo, PacketWriter};
+
+use super::flac::VorbisComments;
+use super::ogg::SERIAL;
+
+mod psy;
+
+use psy::Psy;
+
+/// floor の値1つあたりの `floor1_inverse_dB_table` の添字の幅 (1.07 dB)
+const FLOOR_MULTIPLIER: u32 = 2;
+/// floor の値の範囲 (`FLOOR_MULTIPLIER` に対応する値)
+const FLOOR_RANGE: u32 = 128;
+/// `floor1_inverse_dB_table[i]` は `exp(FLOOR_STEP * (i - 255))`
+const FLOOR_STEP: f64 = 0.06296130868913505;
+/// 予測値が目標の値からこの範囲だけ下にあれば、予測値をそのまま使う
+const FLOOR_TOLERANCE: i32 = 1;
+/// floor を係数の最大値のこれ分の1より下げない (残差が符号帳の範囲 ±248 に収まるように)
+const FLOOR_PEAK_RATIO: f64 = 200.0;
+/// floor 1 の1パーティションあたりのポスト数
+const FLOOR_CLASS_DIMENSIONS: usize = 2;
+
+const PARTITION_SIZE: usize = 16;
+/// residue の分類の符号語1つあたりのパーティション数
+const CLASSWORDS: usize = 2;
+
+const BOOK_FLOOR: usize = 0;
+const BOOK_CLASS: usize = 1;
+
+/// residue の分類ごとの (絶対値の上限, パスごとの符号帳)
+const RESIDUE_CLASSES: [(i32, [Option<usize>; 2]); 6] = [
+    (0, [None, None]),
+    (1, [Some(2), None]),
+    (2, [Some(3), None]),
+    (4, [Some(4), None]),
+    (8, [Some(5), None]),
+    // 16 単位の粗い値に -8〜8 を足す
+    (248, [Some(6), Some(5)]),
+];
+
+/// 格子 VQ の符号帳の (次元, 最小値, 間隔, 値の数, 分布の傾き)
+const LATTICE_BOOKS: [(usize, i32, i32, u32, f64); 5] = [
+    (4, -1, 1, 3, 1.2),
+    (2, -2, 1, 5, 0.8),
+    (2, -4, 1, 9, 0.5),
+    (2, -8, 1, 17, 0.3),
+    (1, -240, 16, 31, 0.3),
+];
+
+/// floor のポストを置く周波数 (Hz)
+const LONG_POSTS: [f64; 29] = [
+    50.0, 100.0, 150.0, 200.0, 250.0, 300.0, 400.0, 500.0, 600.0, 700.0, 800.0, 1000.0, 1200.0,
+    1400.0, 1600.0, 2000.0, 2400.0, 2800.0, 3200.0, 4000.0, 4800.0, 5600.0, 6400.0, 8000.0, 9600.0,
+    11200.0, 12800.0, 16000.0, 19200.0,
+];
+const SHORT_POSTS: [f64; 13] = [
+    200.0, 400.0, 600.0, 800.0, 1200.0, 1600.0, 2400.0, 3200.0, 4800.0, 6400.0, 9600.0, 12800.0,
+    16000.0,
+];
+
+/// 符号の長さを抑えるため、全ての値に均等に割り振る確率
+const HUFFMAN_FLATTEN: f64 = 1e-3;
+
+/// 立ち上がりを調べる区間の長さ
+const ATTACK_SEGMENT: usize = 128;
+/// 直前の `ATTACK_HISTORY` 区間の最大値に対するエネルギー比がこれを超えたら立ち上がりとみなす
+const ATTACK_RATIO: f64 = 8.0;
+/// 声門パルスの間隔より長く取り、周期的なパルスを立ち上がりとみなさない
+const ATTACK_HISTORY: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VorbisOptions {
+    /// 0.0〜10.0。大きいほど高音質・高ビットレート
+    pub quality: f32,
+}
+
+impl Default for VorbisOptions {
+    fn default() -> Self {
+        Self { quality: 4.0 }
+    }
+}
+
+impl VorbisOptions {
+    pub fn with_quality(mut self, quality: f32) -> Self {
+        self.quality = quality;
+        self
+    }
+}
+
+/// Vorbis のビット列 (下位ビットから詰める)
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: vec![],
+            bit: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        for i in 0..bits {
+            if self.bit == 0 {
+                self.bytes.push(0);
+            }
+            if value >> i & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 1 << self.bit;
+            }
+            self.bit = (self.bit + 1) % 8;
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write(byte as u32, 8);
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// 値を表すのに必要なビット数
+fn ilog(value: u32) -> u32 {
+    32 - value.leading_zeros()
+}
+
+/// Vorbis の 32 bit 浮動小数点数 (整数だけを扱う)
+fn pack_float(value: i32) -> u32 {
+    let sign = if value < 0 { 0x8000_0000 } else { 0 };
+    sign | 788 << 21 | value.unsigned_abs()
+}
+
+/// 出現確率からハフマン符号の長さを求める
+///
+/// 確率の一部を均等に割り振り、符号の長さを 32 bit 以内に収める
+fn huffman_lengths(probabilities: &[f64]) -> Vec<u8> {
+    let total: f64 = probabilities.iter().sum();
+    let uniform = HUFFMAN_FLATTEN / probabilities.len() as f64;
+    let mut heap: BinaryHeap<_> = probabilities
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let p = p / total * (1.0 - HUFFMAN_FLATTEN) + uniform;
+            Reverse(((p * 1e12) as u64, i, vec![i]))
+        })
+        .collect();
+    let mut lengths = vec![0u8; probabilities.len()];
+    let mut order = probabilities.len();
+
+    while heap.len() > 1 {
+        let Reverse((a, _, mut left)) = heap.pop().unwrap();
+        let Reverse((b, _, right)) = heap.pop().unwrap();
+        for &i in left.iter().chain(&right) {
+            lengths[i] += 1;
+        }
+        left.extend(right);
+        heap.push(Reverse((a + b, order, left)));
+        order += 1;
+    }
+
+    lengths
+}
+
+/// 符号の長さから、デコーダと同じ規則で符号語を割り当てる (上位ビットから読む)
+fn codewords(lengths: &[u8]) -> Vec<u32> {
+    let mut marker = [0u32; 33];
+    let mut codes = Vec::with_capacity(lengths.len());
+
+    for &length in lengths {
+        let length = length as usize;
+        let mut entry = marker[length];
+        codes.push(entry);
+
+        for j in (1..=length).rev() {
+            if marker[j] & 1 == 1 {
+                marker[j] = match j {
+                    1 => marker[1] + 1,
+                    _ => marker[j - 1] << 1,
+                };
+                break;
+            }
+            marker[j] += 1;
+        }
+
+        for j in length + 1..33 {
+            if marker[j] >> 1 != entry {
+                break;
+            }
+            entry = marker[j];
+            marker[j] = marker[j - 1] << 1;
+        }
+    }
+
+    codes
+}
+
+struct Codebook {
+    dimensions: usize,
+    lengths: Vec<u8>,
+    codes: Vec<u32>,
+    /// 格子 VQ の (最小値, 間隔, 値の数)
+    lattice: Option<(i32, i32, u32)>,
+}
+
+impl Codebook {
+    /// 値を持たない (添字だけを使う) 符号帳。`dimensions` は residue の分類では1語あたりのパーティション数
+    fn scalar(dimensions: usize, probabilities: &[f64]) -> Self {
+        let lengths = huffman_lengths(probabilities);
+        Self {
+            dimensions,
+            codes: codewords(&lengths),
+            lengths,
+            lattice: None,
+        }
+    }
+
+    /// 各次元の値が `min + delta * i` (`i < values`) の格子。絶対値の小さいものほど短い符号にする
+    fn lattice(dimensions: usize, min: i32, delta: i32, values: u32, slope: f64) -> Self {
+        let entries = values.pow(dimensions as u32) as usize;
+        let probabilities: Vec<_> = (0..entries)
+            .map(|entry| {
+                let magnitude: i32 = (0..dimensions)
+                    .map(|d| {
+                        let i = (entry / values.pow(d as u32) as usize) % values as usize;
+                        (min + delta * i as i32).abs() / delta
+                    })
+                    .sum();
+                (-slope * magnitude as f64).exp()
+            })
+            .collect();
+        let lengths = huffman_lengths(&probabilities);
+
+        Self {
+            dimensions,
+            codes: codewords(&lengths),
+            lengths,
+            lattice: Some((min, delta, values)),
+        }
+    }
+
+    fn write_header(&self, writer: &mut BitWriter) {
+        writer.write(0x564342, 24);
+        writer.write(self.dimensions as u32, 16);
+        writer.write(self.lengths.len() as u32, 24);
+        // ordered, sparse
+        writer.write(0, 1);
+        writer.write(0, 1);
+        for &length in &self.lengths {
+            writer.write(length as u32 - 1, 5);
+        }
+
+        match self.lattice {
+            None => writer.write(0, 4),
+            Some((min, delta, values)) => {
+                writer.write(1, 4);
+                writer.write(pack_float(min), 32);
+                writer.write(pack_float(delta), 32);
+                let bits = ilog(values - 1);
+                writer.write(bits - 1, 4);
+                // sequence_p
+                writer.write(0, 1);
+                for i in 0..values {
+                    writer.write(i, bits);
+                }
+            }
+        }
+    }
+
+    fn write_entry(&self, writer: &mut BitWriter, entry: usize) {
+        let (code, length) = (self.codes[entry], self.lengths[entry] as u32);
+        for bit in (0..length).rev() {
+            writer.write(code >> bit & 1, 1);
+        }
+    }
+
+    /// `values` を `dimensions` 個ずつ符号化する (格子上の値であること)
+    fn write_vector(&self, writer: &mut BitWriter, values: &[i32]) {
+        let (min, delta, count) = self.lattice.expect("lattice codebook");
+
+        for chunk in values.chunks(self.dimensions) {
+            let entry = chunk.iter().rev().fold(0, |entry, &value| {
+                entry * count as usize + ((value - min) / delta) as usize
+            });
+            self.write_entry(writer, entry);
+        }
+    }
+}
+
+fn codebooks() -> Vec<Codebook> {
+    // floor の値 (0 が最も多い)
+    let floor: Vec<_> = (0..FLOOR_RANGE).map(|v| 0.8f64.powi(v as i32)).collect();
+
+    // 分類の組 (高い周波数ほど 0 が多い)
+    let class_probabilities = [0.35, 0.25, 0.15, 0.12, 0.09, 0.04];
+    let classes = RESIDUE_CLASSES.len();
+    let class: Vec<_> = (0..classes.pow(CLASSWORDS as u32))
+        .map(|entry| {
+            (0..CLASSWORDS)
+                .map(|i| class_probabilities[entry / classes.pow(i as u32) % classes])
+                .product()
+        })
+        .collect();
+
+    [
+        Codebook::scalar(1, &floor),
+        Codebook::scalar(CLASSWORDS, &class),
+    ]
+    .into_iter()
+    .chain(
+        LATTICE_BOOKS
+            .iter()
+            .map(|&(dimensions, min, delta, values, slope)| {
+                Codebook::lattice(dimensions, min, delta, values, slope)
+            }),
+    )
+    .collect()
+}
+
+/// `exp(-2πi k / n)` を使う基数2の FFT
+fn fft(data: &mut [(f64, f64)], twiddles: &[(f64, f64)]) {
+    let n = data.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let stride = n / len;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (wr, wi) = twiddles[k * stride];
+                let (ar, ai) = data[start + k];
+                let (br, bi) = data[start + k + len / 2];
+                let (tr, ti) = (br * wr - bi * wi, br * wi + bi * wr);
+                data[start + k] = (ar + tr, ai + ti);
+                data[start + k + len / 2] = (ar - tr, ai - ti);
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// `n` サンプルから `n / 2` 個の係数を求める MDCT
+///
+/// `X[k] = Σ x[j] cos(2π/n (j + 1/2 + n/4)(k + 1/2))` を、長さ `n / 4` の FFT で計算する
+struct Mdct {
+    n: usize,
+    twiddles: Vec<(f64, f64)>,
+    /// 前後にかける回転
+    pre: Vec<(f64, f64)>,
+    post: Vec<(f64, f64)>,
+}
+
+impl Mdct {
+    fn new(n: usize) -> Self {
+        let m = n / 2;
+        let rotate = |angle: f64| (angle.cos(), -angle.sin());
+
+        Self {
+            n,
+            twiddles: (0..m / 2)
+                .map(|k| rotate(2.0 * PI * k as f64 / (m / 2) as f64))
+                .collect(),
+            pre: (0..m / 2)
+                .map(|i| rotate(PI * i as f64 / m as f64))
+                .collect(),
+            post: (0..m / 2)
+                .map(|k| rotate(PI * (k as f64 + 0.25) / m as f64))
+                .collect(),
+        }
+    }
+
+    fn forward(&self, input: &[f64]) -> Vec<f64> {
+        let m = self.n / 2;
+        let half = m / 2;
+
+        // 長さ m の DCT-IV に折り返す
+        let fold = |j: usize| {
+            let a = if j >= half { input[j - half] } else { 0.0 };
+            let b = if j < half { input[3 * half + j] } else { 0.0 };
+            a - input[3 * half - 1 - j] - b
+        };
+
+        let mut data: Vec<_> = (0..half)
+            .map(|i| {
+                let (re, im) = (fold(2 * i), fold(m - 1 - 2 * i));
+                let (wr, wi) = self.pre[i];
+                (re * wr - im * wi, re * wi + im * wr)
+            })
+            .collect();
+
+        fft(&mut data, &self.twiddles);
+
+        let mut output = vec![0.0; m];
+        for (k, &(re, im)) in data.iter().enumerate() {
+            let (wr, wi) = self.post[k];
+            output[2 * k] = re * wr - im * wi;
+            output[m - 1 - 2 * k] = -(re * wi + im * wr);
+        }
+        output
+    }
+}
+
+/// 窓の片側の傾斜部分 (`len` サンプル、立ち上がり)
+fn slope(len: usize) -> impl DoubleEndedIterator<Item = f64> {
+    (0..len).map(move |i| {
+        let s = ((i as f64 + 0.5) / len as f64 * PI / 2.0).sin();
+        (PI / 2.0 * s * s).sin()
+    })
+}
+
+/// 前後のブロックの長さに合わせた窓
+fn window(n: usize, previous: usize, next: usize) -> Vec<f64> {
+    let mut window = vec![0.0; n];
+
+    let left = n.min(previous) / 2;
+    let left_start = n / 4 - left / 2;
+    for (w, s) in window[left_start..].iter_mut().zip(slope(left)) {
+        *w = s;
+    }
+    window[left_start + left..n / 2].fill(1.0);
+
+    let right = n.min(next) / 2;
+    let right_start = 3 * n / 4 - right / 2;
+    window[n / 2..right_start].fill(1.0);
+    for (w, s) in window[right_start..].iter_mut().zip(slope(right).rev()) {
+        *w = s;
+    }
+
+    window
+}
+
+/// floor 1 の設定と、デコーダと同じ曲線の復元
+struct Floor {
+    /// 符号化する順のポストの位置 (先頭の2つは 0 と `n / 2`)
+    posts: Vec<usize>,
+    /// 3つ目以降のポストの予測に使う (low, high) の添字
+    neighbors: Vec<(usize, usize)>,
+    /// 位置の順に並べたポストの添字
+    sorted: Vec<usize>,
+    rangebits: u32,
+}
+
+impl Floor {
+    fn new(n: usize, sample_rate: u32, frequencies: &[f64]) -> Self {
+        let half = n / 2;
+        let mut interior: Vec<_> = frequencies
+            .iter()
+            .map(|hz| (hz / (sample_rate as f64 / 2.0) * half as f64).round() as usize)
+            .filter(|&x| 0 < x && x < half)
+            .collect();
+        interior.dedup();
+        interior.truncate(interior.len() / FLOOR_CLASS_DIMENSIONS * FLOOR_CLASS_DIMENSIONS);
+
+        // 粗いものから順に並べ、両隣から予測できるようにする
+        let all: Vec<_> = [0].into_iter().chain(interior).chain([half]).collect();
+        let mut posts = vec![0, half];
+        let mut queue = VecDeque::from([(0, all.len() - 1)]);
+        while let Some((low, high)) = queue.pop_front() {
+            if high - low < 2 {
+                continue;
+            }
+            let middle = (low + high) / 2;
+            posts.push(all[middle]);
+            queue.push_back((low, middle));
+            queue.push_back((middle, high));
+        }
+
+        let neighbors = (0..posts.len())
+            .map(|i| {
+                let before = &posts[..i];
+                let low = (0..i)
+                    .filter(|&j| before[j] < posts[i])
+                    .max_by_key(|&j| before[j])
+                    .unwrap_or(0);
+                let high = (0..i)
+                    .filter(|&j| before[j] > posts[i])
+                    .min_by_key(|&j| before[j])
+                    .unwrap_or(1);
+                (low, high)
+            })
+            .collect();
+
+        let mut sorted: Vec<_> = (0..posts.len()).collect();
+        sorted.sort_by_key(|&i| posts[i]);
+
+        Self {
+            posts,
+            neighbors,
+            sorted,
+            rangebits: ilog(half as u32 - 1),
+        }
+    }
+
+    fn partitions(&self) -> usize {
+        (self.posts.len() - 2) / FLOOR_CLASS_DIMENSIONS
+    }
+
+    fn write_header(&self, writer: &mut BitWriter) {
+        writer.write(1, 16);
+        writer.write(self.partitions() as u32, 5);
+        for _ in 0..self.partitions() {
+            writer.write(0, 4);
+        }
+
+        // クラス 0: サブクラス無し、値は `BOOK_FLOOR` で符号化する
+        writer.write(FLOOR_CLASS_DIMENSIONS as u32 - 1, 3);
+        writer.write(0, 2);
+        writer.write(BOOK_FLOOR as u32 + 1, 8);
+
+        writer.write(FLOOR_MULTIPLIER - 1, 2);
+        writer.write(self.rangebits, 4);
+        for &x in &self.posts[2..] {
+            writer.write(x as u32, self.rangebits);
+        }
+    }
+
+    /// 値 `y` の位置 `x` における直線上の値
+    fn render_point(x0: usize, y0: i32, x1: usize, y1: i32, x: usize) -> i32 {
+        let dy = y1 - y0;
+        let offset = dy.abs() * (x - x0) as i32 / (x1 - x0) as i32;
+        if dy < 0 {
+            y0 - offset
+        } else {
+            y0 + offset
+        }
+    }
+
+    fn render_line(x0: usize, y0: i32, x1: usize, y1: i32, curve: &mut [i32]) {
+        let dy = y1 - y0;
+        let adx = (x1 - x0) as i32;
+        let base = dy / adx;
+        let sy = if dy < 0 { base - 1 } else { base + 1 };
+        let ady = dy.abs() - base.abs() * adx;
+
+        let mut y = y0;
+        let mut err = 0;
+        curve[x0] = y;
+        for value in &mut curve[x0 + 1..x1] {
+            err += ady;
+            if err >= adx {
+                err -= adx;
+                y += sy;
+            } else {
+                y += base;
+            }
+            *value = y;
+        }
+    }
+
+    /// デコーダが送られた値と予測値から floor の値を復元する
+    fn unwrap(value: i32, predicted: i32) -> i32 {
+        let high_room = FLOOR_RANGE as i32 - predicted;
+        let low_room = predicted;
+        let room = 2 * high_room.min(low_room);
+
+        if value >= room {
+            if high_room > low_room {
+                value - low_room + predicted
+            } else {
+                predicted - value + high_room - 1
+            }
+        } else if value % 2 == 1 {
+            predicted - (value + 1) / 2
+        } else {
+            predicted + value / 2
+        }
+    }
+
+    /// 目標の値 (ポストの順) を符号化し、デコーダが復元する曲線 (`floor1_inverse_dB_table` の添字) を返す
+    fn encode(&self, targets: &[i32], writer: &mut BitWriter, book: &Codebook) -> Vec<i32> {
+        let count = self.posts.len();
+        let mut values = vec![0; count];
+        let mut final_y = vec![0; count];
+        let mut used = vec![false; count];
+
+        final_y[0] = targets[0];
+        final_y[1] = targets[1];
+        used[0] = true;
+        used[1] = true;
+
+        for i in 2..count {
+            let (low, high) = self.neighbors[i];
+            let predicted = Self::render_point(
+                self.posts[low],
+                final_y[low],
+                self.posts[high],
+                final_y[high],
+                self.posts[i],
+            );
+
+            if (targets[i] - FLOOR_TOLERANCE..=targets[i]).contains(&predicted) {
+                final_y[i] = predicted;
+                continue;
+            }
+
+            let value = (1..FLOOR_RANGE as i32)
+                .min_by_key(|&v| (Self::unwrap(v, predicted) - targets[i]).abs())
+                .unwrap();
+            values[i] = value;
+            final_y[i] = Self::unwrap(value, predicted);
+            used[low] = true;
+            used[high] = true;
+            used[i] = true;
+        }
+
+        // nonzero
+        writer.write(1, 1);
+        let bits = ilog(FLOOR_RANGE - 1);
+        writer.write(final_y[0] as u32, bits);
+        writer.write(final_y[1] as u32, bits);
+        for &value in &values[2..] {
+            book.write_entry(writer, value as usize);
+        }
+
+        let half = self.posts[1];
+        let mut curve = vec![0; half];
+        let (mut lx, mut ly) = (0, final_y[0] * FLOOR_MULTIPLIER as i32);
+        for &i in &self.sorted[1..] {
+            if used[i] {
+                let (hx, hy) = (self.posts[i], final_y[i] * FLOOR_MULTIPLIER as i32);
+                Self::render_line(lx, ly, hx, hy, &mut curve);
+                (lx, ly) = (hx, hy);
+            }
+        }
+
+        curve
+    }
+}
+
+/// ブロックの長さごとの設定
+struct BlockConfig {
+    n: usize,
+    mdct: Mdct,
+    floor: Floor,
+    psy: Psy,
+}
+
+struct Encoder {
+    sample_rate: u32,
+    short: BlockConfig,
+    long: BlockConfig,
+    books: Vec<Codebook>,
+}
+
+impl Encoder {
+    fn new(sample_rate: u32, options: &VorbisOptions) -> Self {
+        let (short, long) = match sample_rate {
+            0..=32000 => (128, 1024),
+            _ => (256, 2048),
+        };
+        let quality = options.quality.clamp(0.0, 10.0) as f64;
+        let config = |n, posts: &[f64]| BlockConfig {
+            n,
+            mdct: Mdct::new(n),
+            floor: Floor::new(n, sample_rate, posts),
+            psy: Psy::new(n, sample_rate, quality),
+        };
+
+        Self {
+            sample_rate,
+            short: config(short, &SHORT_POSTS),
+            long: config(long, &LONG_POSTS),
+            books: codebooks(),
+        }
+    }
+
+    fn config(&self, n: usize) -> &BlockConfig {
+        if n == self.short.n {
+            &self.short
+        } else {
+            &self.long
+        }
+    }
+
+    fn identification_header(&self) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write(1, 8);
+        writer.write_bytes(b"vorbis");
+        writer.write(0, 32);
+        writer.write(1, 8);
+        writer.write(self.sample_rate, 32);
+        // 最大・公称・最小ビットレート (指定なし)
+        writer.write(0, 32);
+        writer.write(0, 32);
+        writer.write(0, 32);
+        writer.write(self.short.n.trailing_zeros(), 4);
+        writer.write(self.long.n.trailing_zeros(), 4);
+        writer.write(1, 1);
+        writer.into_bytes()
+    }
+
+    fn comment_header(comments: &VorbisComments) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write(3, 8);
+        writer.write_bytes(b"vorbis");
+        writer.write_bytes(&comments.to_bytes());
+        writer.write(1, 1);
+        writer.into_bytes()
+    }
+
+    /// floor・residue・mapping・mode はどれも短いブロック用が 0、長いブロック用が 1
+    fn setup_header(&self) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write(5, 8);
+        writer.write_bytes(b"vorbis");
+
+        writer.write(self.books.len() as u32 - 1, 8);
+        for book in &self.books {
+            book.write_header(&mut writer);
+        }
+
+        // time domain transforms (未使用)
+        writer.write(0, 6);
+        writer.write(0, 16);
+
+        writer.write(1, 6);
+        for config in [&self.short, &self.long] {
+            config.floor.write_header(&mut writer);
+        }
+
+        writer.write(1, 6);
+        for config in [&self.short, &self.long] {
+            writer.write(1, 16);
+            writer.write(0, 24);
+            writer.write(config.n as u32 / 2, 24);
+            writer.write(PARTITION_SIZE as u32 - 1, 24);
+            writer.write(RESIDUE_CLASSES.len() as u32 - 1, 6);
+            writer.write(BOOK_CLASS as u32, 8);
+
+            for (_, books) in RESIDUE_CLASSES {
+                let cascade =
+                    books
+                        .iter()
+                        .enumerate()
+                        .fold(0, |cascade, (pass, book)| match book {
+                            Some(_) => cascade | 1 << pass,
+                            None => cascade,
+                        });
+                writer.write(cascade & 7, 3);
+                writer.write(0, 1);
+            }
+            for (_, books) in RESIDUE_CLASSES {
+                for book in books.into_iter().flatten() {
+                    writer.write(book as u32, 8);
+                }
+            }
+        }
+
+        writer.write(1, 6);
+        for index in 0..2 {
+            writer.write(0, 16);
+            // サブマップ・チャンネル結合なし、予約
+            writer.write(0, 1);
+            writer.write(0, 1);
+            writer.write(0, 2);
+            writer.write(0, 8);
+            writer.write(index, 8);
+            writer.write(index, 8);
+        }
+
+        writer.write(1, 6);
+        for index in 0..2 {
+            writer.write(index, 1);
+            writer.write(0, 16);
+            writer.write(0, 16);
+            writer.write(index, 8);
+        }
+
+        writer.write(1, 1);
+        writer.into_bytes()
+    }
+
+    /// 急な立ち上がりを含む `ATTACK_SEGMENT` 単位の区間
+    fn attacks(samples: &[i16]) -> Vec<bool> {
+        let energies: Vec<f64> = samples
+            .chunks(ATTACK_SEGMENT)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut previous = match i {
+                    0 => 0.0,
+                    _ => samples[i * ATTACK_SEGMENT - 1] as f64,
+                };
+                chunk
+                    .iter()
+                    .map(|&s| {
+                        let d = s as f64 - previous;
+                        previous = s as f64;
+                        d * d
+                    })
+                    .sum()
+            })
+            .collect();
+
+        (0..energies.len())
+            .map(|i| {
+                let previous = energies[i.saturating_sub(ATTACK_HISTORY)..i]
+                    .iter()
+                    .fold(0.0, |a: f64, &b| a.max(b));
+                energies[i] > ATTACK_RATIO * previous + ATTACK_SEGMENT as f64 * 1e4
+            })
+            .collect()
+    }
+
+    /// 各ブロックの長さ。最初のブロックの中心を先頭のサンプルに合わせ、最後のブロックの中心が末尾を越えるまで並べる
+    fn block_sizes(&self, samples: &[i16]) -> Vec<usize> {
+        let (short, long) = (self.short.n as i64, self.long.n as i64);
+        let attacks = Self::attacks(samples);
+        let has_attack = |range: Range<i64>| {
+            let start = (range.start.max(0) as usize / ATTACK_SEGMENT).min(attacks.len());
+            let end = (range.end.max(0) as usize).div_ceil(ATTACK_SEGMENT);
+            attacks[start..end.clamp(start, attacks.len())]
+                .iter()
+                .any(|&a| a)
+        };
+
+        let len = samples.len() as i64;
+        let mut sizes = vec![];
+        let mut center = 0;
+        let mut previous = long;
+
+        while sizes.is_empty() || center < len {
+            let start = center;
+            if !sizes.is_empty() {
+                center += previous / 4;
+            }
+            // 長いブロックの窓が立ち上がりにかからなければ長いブロックにする
+            let n = match has_attack(start..center + long / 2 + short / 4) {
+                true => short,
+                false => long,
+            };
+            if !sizes.is_empty() {
+                center += n / 4;
+            }
+            sizes.push(n as usize);
+            previous = n;
+        }
+
+        sizes
+    }
+
+    fn encode_block(
+        &self,
+        samples: &[i16],
+        center: i64,
+        (previous, n, next): (usize, usize, usize),
+    ) -> Vec<u8> {
+        let config = self.config(n);
+        let half = n / 2;
+        let start = center - half as i64;
+
+        let window = window(n, previous, next);
+        let input: Vec<_> = window
+            .iter()
+            .enumerate()
+            .map(|(j, w)| {
+                let position = start + j as i64;
+                let sample = match position {
+                    0.. if (position as usize) < samples.len() => samples[position as usize],
+                    _ => 0,
+                };
+                sample as f64 / 32768.0 * w
+            })
+            .collect();
+        let scale = 2.0 / half as f64;
+        let spectrum: Vec<_> = config
+            .mdct
+            .forward(&input)
+            .into_iter()
+            .map(|x| x * scale)
+            .collect();
+
+        let mut writer = BitWriter::new();
+        // パケットの種類 (音声)、mode
+        writer.write(0, 1);
+        writer.write((n == self.long.n) as u32, 1);
+        if n == self.long.n {
+            writer.write((previous == self.long.n) as u32, 1);
+            writer.write((next == self.long.n) as u32, 1);
+        }
+
+        // 量子化の幅 (floor) を 2√T にすると、丸めの誤差のエネルギーは許容ノイズ T 以下になる
+        let steps: Vec<_> = config
+            .psy
+            .thresholds(&spectrum)
+            .into_iter()
+            .map(|t| 2.0 * t.sqrt())
+            .collect();
+
+        // floor の目標: 両隣のポストまでの範囲で最も小さい幅 (直線で結んでも越えないように)。
+        // ただし範囲内の最大の係数が残差の範囲に収まるところまでしか下げない
+        let floor = &config.floor;
+        let targets: Vec<i32> = floor
+            .posts
+            .iter()
+            .map(|&x| {
+                let position = floor.sorted.partition_point(|&i| floor.posts[i] < x);
+                let neighbor = |i: Option<usize>| i.map(|i| floor.posts[floor.sorted[i]]);
+                let low = neighbor(position.checked_sub(1)).unwrap_or(0);
+                let high = neighbor(Some(position + 1).filter(|&i| i < floor.sorted.len()))
+                    .map_or(half, |h| h + 1)
+                    .min(half);
+                let step = steps[low..high].iter().copied().fold(f64::MAX, f64::min);
+                let peak = spectrum[low..high]
+                    .iter()
+                    .fold(0.0, |a: f64, x| a.max(x.abs()));
+                let value = step.max(peak / FLOOR_PEAK_RATIO);
+                let index = 255.0 + value.ln() / FLOOR_STEP;
+                ((index / FLOOR_MULTIPLIER as f64).floor() as i32).clamp(0, FLOOR_RANGE as i32 - 1)
+            })
+            .collect();
+
+        let mut floor_writer = BitWriter::new();
+        let curve = floor.encode(&targets, &mut floor_writer, &self.books[BOOK_FLOOR]);
+
+        let residue: Vec<i32> = (0..half)
+            .map(|k| {
+                let value = ((curve[k] as f64 - 255.0) * FLOOR_STEP).exp();
+                (spectrum[k] / value).round().clamp(-248.0, 248.0) as i32
+            })
+            .collect();
+
+        // 全て 0 なら floor を使わない (無音)
+        if residue.iter().all(|&r| r == 0) {
+            writer.write(0, 1);
+            return writer.into_bytes();
+        }
+
+        for (i, &byte) in floor_writer.bytes.iter().enumerate() {
+            let bits = match i + 1 == floor_writer.bytes.len() && floor_writer.bit != 0 {
+                true => floor_writer.bit,
+                false => 8,
+            };
+            writer.write(byte as u32, bits);
+        }
+
+        self.encode_residue(&residue, &mut writer);
+
+        writer.into_bytes()
+    }
+
+    fn encode_residue(&self, residue: &[i32], writer: &mut BitWriter) {
+        let partitions: Vec<_> = residue.chunks(PARTITION_SIZE).collect();
+        let classes: Vec<_> = partitions
+            .iter()
+            .map(|partition| {
+                let max = partition.iter().map(|r| r.abs()).max().unwrap_or(0);
+                RESIDUE_CLASSES
+                    .iter()
+                    .position(|&(limit, _)| max <= limit)
+                    .unwrap()
+            })
+            .collect();
+
+        for pass in 0..2 {
+            for group in (0..partitions.len()).step_by(CLASSWORDS) {
+                if pass == 0 {
+                    let entry = (0..CLASSWORDS).fold(0, |entry, i| {
+                        entry * RESIDUE_CLASSES.len() + classes.get(group + i).copied().unwrap_or(0)
+                    });
+                    self.books[BOOK_CLASS].write_entry(writer, entry);
+                }
+
+                for index in group..(group + CLASSWORDS).min(partitions.len()) {
+                    let Some(book) = RESIDUE_CLASSES[classes[index]].1[pass] else {
+                        continue;
+                    };
+                    let partition = partitions[index];
+                    let values: Vec<_> = match (RESIDUE_CLASSES[classes[index]].1, pass) {
+                        // 粗い値とその残り
+                        ([Some(_), Some(_)], 0) => partition
+                            .iter()
+                            .map(|&r| coarse(r) * LATTICE_BOOKS[4].2)
+                            .collect(),
+                        ([Some(_), Some(_)], _) => partition
+                            .iter()
+                            .map(|&r| r - coarse(r) * LATTICE_BOOKS[4].2)
+                            .collect(),
+                        _ => partition.to_vec(),
+                    };
+                    self.books[book].write_vector(writer, &values);
+                }
+            }
+        }
+    }
+}
+
+/// 16 単位に丸めた値 (-15〜15)
+fn coarse(value: i32) -> i32 {
+    ((value as f64 / 16.0).round() as i32).clamp(-15, 15)
+}
+
+pub(super) fn write_ogg_vorbis<W: Write>(
+    writer: W,
+    samples: &[i16],
+    sample_rate: u32,
+    comments: &VorbisComments,
+    options: &VorbisOptions,
+) -> io::Result<()> {
+    if sample_rate == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sample rate must be non-zero",
+        ));
+    }
+
+    let encoder = Encoder::new(sample_rate, options);
+    let sizes = match samples.is_empty() {
+        true => vec![],
+        false => encoder.block_sizes(samples),
+    };
+
+    let mut writer = PacketWriter::new(writer);
+
+    writer.write_packet(
+        encoder.identification_header().into(),
+        SERIAL,
+        PacketWriteEndInfo::EndPage,
+        0,
+    )?;
+    writer.write_packet(
+        Encoder::comment_header(comments).into(),
+        SERIAL,
+        PacketWriteEndInfo::NormalPacket,
+        0,
+    )?;
+
+    // 音声が無ければヘッダパケットでストリームを終える
+    let last_header = match sizes.is_empty() {
+        true => PacketWriteEndInfo::EndStream,
+        false => PacketWriteEndInfo::EndPage,
+    };
+    writer.write_packet(encoder.setup_header().into(), SERIAL, last_header, 0)?;
+
+    let len = samples.len() as u64;
+    let mut center = 0;
+    for (i, &n) in sizes.iter().enumerate() {
+        let previous = match i {
+            0 => n,
+            _ => sizes[i - 1],
+        };
+        let next = sizes.get(i + 1).copied().unwrap_or(n);
+        if i > 0 {
+            center += (previous / 4 + n / 4) as u64;
+        }
+
+        let packet = encoder.encode_block(samples, center as i64, (previous, n, next));
+        let end = match i + 1 == sizes.len() {
+            true => PacketWriteEndInfo::EndStream,
+            false => PacketWriteEndInfo::NormalPacket,
+        };
+        writer.write_packet(packet.into(), SERIAL, end, center.min(len))?;
+    }
+
+    writer.into_inner().flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mdct_matches_definition() {
+        let n = 64;
+        let input: Vec<_> = (0..n).map(|i| ((i * 7 % 13) as f64 - 6.0) / 6.0).collect();
+
+        let output = Mdct::new(n).forward(&input);
+
+        for (k, &value) in output.iter().enumerate() {
+            let expected: f64 = input
+                .iter()
+                .enumerate()
+                .map(|(j, x)| {
+                    let phase = 2.0 * PI / n as f64;
+                    x * (phase * (j as f64 + 0.5 + n as f64 / 4.0) * (k as f64 + 0.5)).cos()
+                })
+                .sum();
+            assert!((value - expected).abs() < 1e-9, "{k}: {value} {expected}");
+        }
+    }
+
+    #[test]
+    fn codebooks_are_complete() {
+        for book in codebooks() {
+            let kraft: f64 = book.lengths.iter().map(|&l| 0.5f64.powi(l as i32)).sum();
+            assert!((kraft - 1.0).abs() < 1e-12);
+            assert!(book.lengths.iter().all(|&l| (1..=32).contains(&l)));
+        }
+    }
+
+    #[test]
+    fn windows_overlap_to_unity() {
+        for (n, previous) in [(2048, 2048), (2048, 256), (256, 256)] {
+            let left = window(n, previous, n);
+            let right = window(previous, previous, n);
+            let overlap = n.min(previous) / 2;
+
+            for i in 0..overlap {
+                let a = left[n / 4 - overlap / 2 + i];
+                let b = right[3 * previous / 4 - overlap / 2 + i];
+                assert!((a * a + b * b - 1.0).abs() < 1e-12);
+            }
+        }
+    }
+
+    fn packets(bytes: Vec<u8>) -> Vec<ogg::Packet> {
+        let mut reader = ogg::PacketReader::new(io::Cursor::new(bytes));
+        std::iter::from_fn(|| reader.read_packet().unwrap()).collect()
+    }
+
+    #[test]
+    fn empty_input_ends_on_setup_header() {
+        let mut bytes = vec![];
+        let options = VorbisOptions::default();
+        write_ogg_vorbis(&mut bytes, &[], 44100, &VorbisComments::new(), &options).unwrap();
+
+        let packets = packets(bytes);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(&packets[2].data[..7], b"\x05vorbis");
+        assert!(packets[2].last_in_stream());
+    }
+
+    #[test]
+    fn granule_is_sample_count() {
+        let samples: Vec<i16> = (0..10000)
+            .map(|i| ((i as f64 * 0.05).sin() * 8000.0) as i16)
+            .collect();
+        let mut bytes = vec![];
+        let options = VorbisOptions::default();
+        write_ogg_vorbis(
+            &mut bytes,
+            &samples,
+            22050,
+            &VorbisComments::new(),
+            &options,
+        )
+        .unwrap();
+
+        let packets = packets(bytes);
+        assert!(packets[3..].iter().all(|p| p.data[0] & 1 == 0));
+        let last = packets.last().unwrap();
+        assert!(last.last_in_stream());
+        assert_eq!(last.absgp_page(), 10000);
+    }
+
+    /// 別の実装 (symphonia) で復号する
+    fn decode(bytes: Vec<u8>) -> Vec<i16> {
+        use symphonia::core::audio::SampleBuffer;
+        use symphonia::core::codecs::{Decoder, DecoderOptions};
+        use symphonia::core::formats::{FormatOptions, FormatReader};
+        use symphonia::core::io::MediaSourceStream;
+        use symphonia::default::codecs::VorbisDecoder;
+        use symphonia::default::formats::OggReader;
+
+        let source = MediaSourceStream::new(Box::new(io::Cursor::new(bytes)), Default::default());
+        let mut reader = OggReader::try_new(source, &FormatOptions::default()).unwrap();
+        let params = reader.default_track().unwrap().codec_params.clone();
+        let mut decoder = VorbisDecoder::try_new(&params, &DecoderOptions::default()).unwrap();
+
+        let mut samples = vec![];
+        while let Ok(packet) = reader.next_packet() {
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buffer.samples());
+        }
+        samples
+    }
+
+    /// 信号対雑音比 (dB)。復号側は末尾を切り詰めないため、重なる範囲で比べる
+    fn snr(original: &[i16], decoded: &[i16]) -> f64 {
+        let (signal, noise) =
+            original
+                .iter()
+                .zip(decoded)
+                .fold((0.0, 0.0), |(signal, noise), (&a, &b)| {
+                    let (a, b) = (a as f64, b as f64);
+                    (signal + a * a, noise + (a - b) * (a - b))
+                });
+        10.0 * (signal / noise).log10()
+    }
+
+    /// 440 Hz (-12 dBFS) と 2500 Hz (-20 dBFS) の和を 1 秒
+    fn two_tones(sample_rate: u32) -> Vec<i16> {
+        (0..sample_rate)
+            .map(|i| {
+                let t = i as f64 / sample_rate as f64;
+                let s = 0.25 * (2.0 * PI * 440.0 * t).sin() + 0.1 * (2.0 * PI * 2500.0 * t).sin();
+                (s * 32767.0) as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decoded_snr_meets_quality() {
+        // (quality, SNR の下限 dB)
+        let levels = [
+            (0.0, 14.0),
+            (2.0, 18.0),
+            (4.0, 25.0),
+            (6.0, 30.0),
+            (8.0, 35.0),
+            (10.0, 40.0),
+        ];
+
+        for sample_rate in [8000, 16000, 22050] {
+            let samples = two_tones(sample_rate);
+            let mut previous = f64::MIN;
+
+            for (quality, min) in levels {
+                let mut bytes = vec![];
+                let options = VorbisOptions::default().with_quality(quality);
+                write_ogg_vorbis(
+                    &mut bytes,
+                    &samples,
+                    sample_rate,
+                    &VorbisComments::new(),
+                    &options,
+                )
+                .unwrap();
+
+                let decoded = decode(bytes);
+                assert!(decoded.len() >= samples.len());
+                let snr = snr(&samples, &decoded);
+                assert!(
+                    snr >= min,
+                    "{sample_rate} Hz, quality {quality}: {snr:.1} dB"
+                );
+                assert!(
+                    snr > previous,
+                    "{sample_rate} Hz, quality {quality}: {snr:.1} dB"
+                );
+                previous = snr;
+            }
+        }
+    }
+}
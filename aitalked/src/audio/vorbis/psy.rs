@@ -0,0 +1,195 @@
+//! 聴覚マスキングから、各係数に許される量子化ノイズを求める
+//!
+//! 係数のエネルギーを 0.5 Bark 以下の帯域にまとめ、Schroeder の拡散関数で周りの帯域に広げる。
+//! 帯域ごとの音の純度 (スペクトル平坦度) でマスキングの強さを変え、最小可聴値 (Terhardt) を下限にする。
+//! 係数の値はフルスケールの正弦波がエネルギー約 1 になる大きさで、これを 96 dB SPL とみなす
+
+use std::ops::Range;
+
+/// 帯域の幅の上限 (Bark)
+const BAND_WIDTH: f64 = 0.5;
+/// 音の純度を調べる範囲 (帯域の中心から前後の Bark)
+const TONALITY_WIDTH: f64 = 0.5;
+/// スペクトル平坦度がこの値 (dB) 以下なら純音とみなす
+const SFM_TONAL: f64 = -60.0;
+/// ノイズによるマスキングの、マスカーからの距離 (dB)
+const NOISE_OFFSET: f64 = 5.5;
+/// 純音によるマスキングの、マスカーからの距離 (`TONE_OFFSET + Bark` dB)
+const TONE_OFFSET: f64 = 14.5;
+/// フルスケールに当たる音圧 (dB SPL)
+const FULL_SCALE_SPL: f64 = 96.0;
+/// `quality` 1 あたりにマスキングの閾値を下げる量 (dB)。`quality` 2 で 0
+const QUALITY_STEP: f64 = 3.0;
+const QUALITY_BASE: f64 = 2.0;
+
+fn bark(hz: f64) -> f64 {
+    13.0 * (0.00076 * hz).atan() + 3.5 * (hz / 7500.0).powi(2).atan()
+}
+
+/// 最小可聴値 (dB SPL)
+fn absolute_threshold(hz: f64) -> f64 {
+    let khz = hz.max(10.0) / 1000.0;
+    3.64 * khz.powf(-0.8) - 6.5 * (-0.6 * (khz - 3.3).powi(2)).exp() + 1e-3 * khz.powi(4)
+}
+
+/// Schroeder の拡散関数 (dB)。`dz` はマスカーから見た Bark の差
+fn spreading(dz: f64) -> f64 {
+    let x = dz + 0.474;
+    15.81 + 7.5 * x - 17.5 * (1.0 + x * x).sqrt()
+}
+
+struct Band {
+    bins: Range<usize>,
+    /// 音の純度を調べる係数の範囲
+    tonality: Range<usize>,
+    bark: f64,
+}
+
+/// ブロックの長さごとのマスキングの計算
+pub(super) struct Psy {
+    bands: Vec<Band>,
+    /// `spreading[i][j]`: 帯域 `j` のエネルギーが帯域 `i` に及ぼす割合
+    spreading: Vec<Vec<f64>>,
+    /// 係数ごとの最小可聴値 (エネルギー)
+    absolute: Vec<f64>,
+    /// マスキングの閾値に足す量 (dB)
+    shift: f64,
+}
+
+impl Psy {
+    pub(super) fn new(n: usize, sample_rate: u32, quality: f64) -> Self {
+        let half = n / 2;
+        let hz = |k: usize| (k as f64 + 0.5) * sample_rate as f64 / n as f64;
+        let barks: Vec<_> = (0..half).map(|k| bark(hz(k))).collect();
+
+        let mut bands = vec![];
+        let mut start = 0;
+        for k in 1..=half {
+            if k == half || barks[k] - barks[start] >= BAND_WIDTH {
+                let center = (barks[start] + barks[k - 1]) / 2.0;
+                let low = barks.partition_point(|&z| z < center - TONALITY_WIDTH);
+                let high = barks.partition_point(|&z| z <= center + TONALITY_WIDTH);
+                bands.push(Band {
+                    bins: start..k,
+                    tonality: low.min(start)..high.max(k),
+                    bark: center,
+                });
+                start = k;
+            }
+        }
+
+        let spreading = bands
+            .iter()
+            .map(|maskee| {
+                bands
+                    .iter()
+                    .map(|masker| 10f64.powf(spreading(maskee.bark - masker.bark) / 10.0))
+                    .collect()
+            })
+            .collect();
+
+        let absolute = (0..half)
+            .map(|k| 10f64.powf((absolute_threshold(hz(k)) - FULL_SCALE_SPL) / 10.0))
+            .collect();
+
+        Self {
+            bands,
+            spreading,
+            absolute,
+            shift: QUALITY_STEP * (quality - QUALITY_BASE),
+        }
+    }
+
+    /// 純音らしさ (0.0 がノイズ、1.0 が純音)
+    fn tonality(energies: &[f64]) -> f64 {
+        if energies.len() < 2 {
+            return 1.0;
+        }
+
+        let len = energies.len() as f64;
+        let arithmetic = energies.iter().sum::<f64>() / len;
+        let geometric = (energies.iter().map(|e| (e + 1e-30).ln()).sum::<f64>() / len).exp();
+        let flatness = 10.0 * (geometric / (arithmetic + 1e-30)).log10();
+
+        (flatness / SFM_TONAL).clamp(0.0, 1.0)
+    }
+
+    /// 各係数に許される量子化ノイズのエネルギー
+    pub(super) fn thresholds(&self, spectrum: &[f64]) -> Vec<f64> {
+        let energies: Vec<_> = spectrum.iter().map(|x| x * x).collect();
+        let band_energies: Vec<f64> = self
+            .bands
+            .iter()
+            .map(|band| energies[band.bins.clone()].iter().sum())
+            .collect();
+
+        let mut thresholds = self.absolute.clone();
+
+        for (i, band) in self.bands.iter().enumerate() {
+            let spread: f64 = self.spreading[i]
+                .iter()
+                .zip(&band_energies)
+                .map(|(s, e)| s * e)
+                .sum();
+
+            let alpha = Self::tonality(&energies[band.tonality.clone()]);
+            let offset = alpha * (TONE_OFFSET + band.bark) + (1.0 - alpha) * NOISE_OFFSET;
+            let masked =
+                spread * 10f64.powf(-(offset + self.shift) / 10.0) / band.bins.len() as f64;
+
+            for threshold in &mut thresholds[band.bins.clone()] {
+                *threshold = threshold.max(masked);
+            }
+        }
+
+        thresholds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bands_cover_every_bin() {
+        for (n, sample_rate) in [(128, 8000), (2048, 44100)] {
+            let psy = Psy::new(n, sample_rate, 4.0);
+            let mut next = 0;
+            for band in &psy.bands {
+                assert_eq!(band.bins.start, next);
+                assert!(
+                    band.tonality.start <= band.bins.start && band.bins.end <= band.tonality.end
+                );
+                next = band.bins.end;
+            }
+            assert_eq!(next, n / 2);
+        }
+    }
+
+    #[test]
+    fn spreading_peaks_at_masker() {
+        assert!(spreading(0.0).abs() < 0.01);
+        assert!(spreading(-1.0) < spreading(1.0));
+        assert!(spreading(3.0) < -20.0);
+    }
+
+    #[test]
+    fn tone_masks_less_than_noise() {
+        let psy = Psy::new(1024, 22050, 4.0);
+        let tone: Vec<_> = (0..512).map(|k| if k == 40 { 0.5 } else { 0.0 }).collect();
+        let noise: Vec<_> = (0..512)
+            .map(|k| {
+                if (20..60).contains(&k) {
+                    (0.25f64 / 40.0).sqrt()
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        // 同じエネルギーでも、純音のほうが許されるノイズが小さい
+        assert!(psy.thresholds(&tone)[40] < psy.thresholds(&noise)[40] / 2.0);
+        // 何も無い所は最小可聴値
+        assert_eq!(psy.thresholds(&tone)[400], psy.absolute[400]);
+    }
+}
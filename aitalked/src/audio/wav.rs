@@ -0,0 +1,424 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::label;
+use crate::preset::VoicePreset;
+use crate::source_map::SourceMap;
+use crate::timeline::Timeline;
+
+pub const CHANNELS: u16 = 1;
+pub const BITS_PER_SAMPLE: u16 = 16;
+const BLOCK_ALIGN: u16 = CHANNELS * BITS_PER_SAMPLE / 8;
+
+/// `RIFF` から `data` チャンクのサイズまで
+pub const HEADER_LEN: u32 = 44;
+
+/// 長さが分からないまま書き出すときに RIFF と `data` のサイズに入れる値
+pub const UNKNOWN_LEN: u32 = u32::MAX;
+
+pub(super) fn data_len(samples: usize) -> io::Result<u32> {
+    samples
+        .checked_mul(BLOCK_ALIGN as usize)
+        .and_then(|len| u32::try_from(len).ok())
+        .filter(|len| len.checked_add(HEADER_LEN - 8).is_some())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too long for a WAV file"))
+}
+
+/// RIFF のサイズはファイル全体から先頭8バイトを除いた `data_len + 36` (と `data` の後ろのチャンク)
+pub(super) fn write_header<W: Write>(
+    writer: &mut W,
+    sample_rate: u32,
+    data_len: u32,
+    trailing_len: u32,
+) -> io::Result<()> {
+    let riff_len = match data_len {
+        UNKNOWN_LEN => UNKNOWN_LEN,
+        len => riff_len(len, trailing_len)?,
+    };
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&riff_len.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&CHANNELS.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * BLOCK_ALIGN as u32).to_le_bytes())?;
+    writer.write_all(&BLOCK_ALIGN.to_le_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+
+    Ok(())
+}
+
+fn riff_len(data_len: u32, trailing_len: u32) -> io::Result<u32> {
+    (data_len + HEADER_LEN - 8)
+        .checked_add(trailing_len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too long for a WAV file"))
+}
+
+pub(super) fn write_samples<W: Write>(writer: &mut W, samples: &[i16]) -> io::Result<()> {
+    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    writer.write_all(&bytes)
+}
+
+/// ファイルなどシークできる出力に少しずつ書き出し、`finish` でヘッダのサイズを書き直す
+#[derive(Debug)]
+pub struct WavWriter<W: Write + Seek> {
+    writer: Option<W>,
+    start: u64,
+    samples: usize,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<Self> {
+        let start = writer.stream_position()?;
+        write_header(&mut writer, sample_rate, 0, 0)?;
+
+        Ok(Self {
+            writer: Some(writer),
+            start,
+            samples: 0,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        data_len(self.samples + samples.len())?;
+        write_samples(self.writer.as_mut().unwrap(), samples)?;
+        self.samples += samples.len();
+        Ok(())
+    }
+
+    pub fn samples_written(&self) -> usize {
+        self.samples
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        self.finish_with_metadata(&WavMetadata::default())
+    }
+
+    /// `data` の後ろにメタデータのチャンクを加えて閉じる
+    pub fn finish_with_metadata(mut self, metadata: &WavMetadata) -> io::Result<W> {
+        let trailing_len = metadata.chunks_len();
+        metadata.write_chunks(self.writer.as_mut().unwrap())?;
+        self.update_header(trailing_len)?;
+        Ok(self.writer.take().unwrap())
+    }
+
+    fn update_header(&mut self, trailing_len: u32) -> io::Result<()> {
+        let data_len = data_len(self.samples)?;
+        let riff_len = riff_len(data_len, trailing_len)?;
+        let writer = self.writer.as_mut().unwrap();
+
+        let end = writer.stream_position()?;
+        writer.seek(SeekFrom::Start(self.start + 4))?;
+        writer.write_all(&riff_len.to_le_bytes())?;
+        writer.seek(SeekFrom::Start(self.start + HEADER_LEN as u64 - 4))?;
+        writer.write_all(&data_len.to_le_bytes())?;
+        writer.seek(SeekFrom::Start(end))?;
+        writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    /// `finish` を呼ばなかった場合もできる限りヘッダを直す
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.update_header(0);
+        }
+    }
+}
+
+/// パイプなどシークできない出力に書き出す
+///
+/// サイズが分からないので、ヘッダには `UNKNOWN_LEN` を入れる。メタデータは書き込めない
+#[derive(Debug)]
+pub struct StreamingWavWriter<W: Write> {
+    writer: W,
+    samples: usize,
+}
+
+impl<W: Write> StreamingWavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<Self> {
+        write_header(&mut writer, sample_rate, UNKNOWN_LEN, 0)?;
+        Ok(Self { writer, samples: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        write_samples(&mut self.writer, samples)?;
+        self.samples += samples.len();
+        Ok(())
+    }
+
+    pub fn samples_written(&self) -> usize {
+        self.samples
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// `data` の後ろに置く `LIST`/`INFO`・`cue `・`LIST`/`adtl` チャンクの内容
+///
+/// 文字列は UTF-8 で書き込む
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WavMetadata {
+    pub info: Vec<([u8; 4], String)>,
+    pub cues: Vec<CuePoint>,
+}
+
+/// `length` が 0 でなければ範囲 (`labl` に加えて `ltxt` の `rgn `) になる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CuePoint {
+    /// サンプル位置
+    pub sample: u32,
+    /// サンプル数
+    pub length: u32,
+    pub label: String,
+}
+
+impl WavMetadata {
+    /// 入力テキスト
+    pub const TEXT: [u8; 4] = *b"ICMT";
+    /// 話者名
+    pub const VOICE: [u8; 4] = *b"IART";
+    /// プリセット
+    pub const PRESET: [u8; 4] = *b"ISBJ";
+    /// エンジンのバージョン
+    pub const SOFTWARE: [u8; 4] = *b"ISFT";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.info.is_empty() && self.cues.is_empty()
+    }
+
+    /// 同じ ID があれば置き換える
+    pub fn set_info(&mut self, id: [u8; 4], value: impl Into<String>) {
+        let value = value.into();
+        match self.info.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, v)) => *v = value,
+            None => self.info.push((id, value)),
+        }
+    }
+
+    pub fn with_info(mut self, id: [u8; 4], value: impl Into<String>) -> Self {
+        self.set_info(id, value);
+        self
+    }
+
+    pub fn with_text(self, text: impl Into<String>) -> Self {
+        self.with_info(Self::TEXT, text)
+    }
+
+    pub fn with_voice(self, voice: impl Into<String>) -> Self {
+        self.with_info(Self::VOICE, voice)
+    }
+
+    /// 話者名とプリセットの値を書き込む
+    pub fn with_preset(self, preset: &VoicePreset) -> Self {
+        self.with_voice(preset.voice_name.clone())
+            .with_info(Self::PRESET, super::preset_summary(preset))
+    }
+
+    pub fn with_engine_version(self, version: impl Into<String>) -> Self {
+        self.with_info(Self::SOFTWARE, version)
+    }
+
+    pub fn add_cue(&mut self, sample: u32, length: u32, label: impl Into<String>) {
+        self.cues.push(CuePoint {
+            sample,
+            length,
+            label: label.into(),
+        });
+    }
+
+    /// `BOOKMARK` を位置、文を範囲として加える (`source` はジョブに渡した文字列か `RichText`)
+    pub fn with_timeline_cues(mut self, source: impl Into<SourceMap>, timeline: &Timeline) -> Self {
+        let labels = label::bookmark_labels(timeline)
+            .into_iter()
+            .chain(label::sentence_labels(source, timeline));
+
+        for label in labels {
+            let start = timeline.tick_to_sample(label.start);
+            let end = timeline.tick_to_sample(label.end).max(start);
+            self.add_cue(
+                start.try_into().unwrap_or(u32::MAX),
+                (end - start).try_into().unwrap_or(u32::MAX),
+                label.text,
+            );
+        }
+
+        self.cues.sort_by_key(|c| c.sample);
+        self
+    }
+
+    fn info_len(&self) -> u32 {
+        self.info
+            .iter()
+            .map(|(_, value)| 8 + padded(value.len() as u32 + 1))
+            .sum()
+    }
+
+    fn adtl_len(&self) -> u32 {
+        self.cues
+            .iter()
+            .map(|cue| {
+                let labl = 8 + 4 + padded(cue.label.len() as u32 + 1);
+                match cue.length {
+                    0 => labl,
+                    _ => labl + 8 + 20,
+                }
+            })
+            .sum()
+    }
+
+    /// `data` の後ろに書くチャンクのバイト数
+    pub fn chunks_len(&self) -> u32 {
+        let mut len = 0;
+
+        if !self.info.is_empty() {
+            len += 12 + self.info_len();
+        }
+
+        if !self.cues.is_empty() {
+            len += 8 + 4 + 24 * self.cues.len() as u32;
+            len += 12 + self.adtl_len();
+        }
+
+        len
+    }
+
+    pub fn write_chunks<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if !self.info.is_empty() {
+            writer.write_all(b"LIST")?;
+            writer.write_all(&(4 + self.info_len()).to_le_bytes())?;
+            writer.write_all(b"INFO")?;
+
+            for (id, value) in &self.info {
+                write_string_chunk(writer, id, value)?;
+            }
+        }
+
+        if self.cues.is_empty() {
+            return Ok(());
+        }
+
+        writer.write_all(b"cue ")?;
+        writer.write_all(&(4 + 24 * self.cues.len() as u32).to_le_bytes())?;
+        writer.write_all(&(self.cues.len() as u32).to_le_bytes())?;
+
+        for (id, cue) in (1u32..).zip(&self.cues) {
+            writer.write_all(&id.to_le_bytes())?;
+            writer.write_all(&cue.sample.to_le_bytes())?;
+            writer.write_all(b"data")?;
+            writer.write_all(&0u32.to_le_bytes())?;
+            writer.write_all(&0u32.to_le_bytes())?;
+            writer.write_all(&cue.sample.to_le_bytes())?;
+        }
+
+        writer.write_all(b"LIST")?;
+        writer.write_all(&(4 + self.adtl_len()).to_le_bytes())?;
+        writer.write_all(b"adtl")?;
+
+        // 名前はすべての cue に `labl` で付け、範囲の長さは `ltxt` (テキストなし) で表す
+        for (id, cue) in (1u32..).zip(&self.cues) {
+            let len = cue.label.len() as u32 + 1;
+            writer.write_all(b"labl")?;
+            writer.write_all(&(4 + len).to_le_bytes())?;
+            writer.write_all(&id.to_le_bytes())?;
+            write_padded_string(writer, &cue.label)?;
+
+            if cue.length != 0 {
+                writer.write_all(b"ltxt")?;
+                writer.write_all(&20u32.to_le_bytes())?;
+                writer.write_all(&id.to_le_bytes())?;
+                writer.write_all(&cue.length.to_le_bytes())?;
+                writer.write_all(b"rgn ")?;
+                // country, language, dialect, code page
+                writer.write_all(&[0; 8])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn padded(len: u32) -> u32 {
+    len + len % 2
+}
+
+/// NUL 終端し、チャンクの長さが奇数なら 1 バイト詰める
+fn write_padded_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    writer.write_all(value.as_bytes())?;
+    writer.write_all(&[0])?;
+    if (value.len() + 1) % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+fn write_string_chunk<W: Write>(writer: &mut W, id: &[u8; 4], value: &str) -> io::Result<()> {
+    writer.write_all(id)?;
+    writer.write_all(&(value.len() as u32 + 1).to_le_bytes())?;
+    write_padded_string(writer, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `LIST adtl` の中のチャンクを (ID, cue の ID, 中身) で返す
+    fn adtl_chunks(bytes: &[u8]) -> Vec<([u8; 4], u32, Vec<u8>)> {
+        let start = bytes.windows(4).position(|w| w == b"adtl").unwrap() + 4;
+        let mut chunks = vec![];
+        let mut rest = &bytes[start..];
+
+        while rest.len() >= 8 {
+            let id: [u8; 4] = rest[..4].try_into().unwrap();
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            let body = &rest[8..8 + len];
+            let cue = u32::from_le_bytes(body[..4].try_into().unwrap());
+            chunks.push((id, cue, body[4..].to_vec()));
+            rest = &rest[8 + padded(len as u32) as usize..];
+        }
+
+        chunks
+    }
+
+    #[test]
+    fn writes_label_for_every_cue() {
+        let mut metadata = WavMetadata::new();
+        metadata.add_cue(0, 0, "mark");
+        metadata.add_cue(100, 50, "文");
+        metadata.add_cue(200, 10, "");
+
+        let mut bytes = vec![];
+        metadata.write_chunks(&mut bytes).unwrap();
+        assert_eq!(bytes.len() as u32, metadata.chunks_len());
+
+        let chunks = adtl_chunks(&bytes);
+        let ids: Vec<_> = chunks.iter().map(|(id, cue, _)| (id, *cue)).collect();
+        assert_eq!(
+            ids,
+            [
+                (b"labl", 1),
+                (b"labl", 2),
+                (b"ltxt", 2),
+                (b"labl", 3),
+                (b"ltxt", 3)
+            ]
+        );
+
+        assert_eq!(chunks[1].2, "文\0".as_bytes());
+        assert_eq!(&chunks[2].2[..8], &[50, 0, 0, 0, b'r', b'g', b'n', b' ']);
+        assert_eq!(chunks[2].2.len(), 16);
+    }
+}
@@ -8,12 +8,65 @@ fn format_sjis_cchar_slice(
     fmt: &mut std::fmt::Formatter,
 ) -> Result<(), std::fmt::Error> {
     use std::fmt::Debug;
+    decode_sjis_cchar_slice(s).fmt(fmt)
+}
+
+pub(crate) fn decode_sjis_cchar_slice(s: &[c_char]) -> String {
     let s = unsafe { std::slice::from_raw_parts(s.as_ptr() as *const u8, s.len()) };
-    let (s, _encoding, _errors) = SHIFT_JIS.decode(s);
-    let s = s.trim_matches(char::from(0));
-    s.fmt(fmt)
+    let len = s.iter().position(|&c| c == 0).unwrap_or(s.len());
+    let (s, _encoding, _errors) = SHIFT_JIS.decode(&s[..len]);
+    s.into_owned()
+}
+
+/// NUL終端を含めて`dst`に収まらない場合や、SJISで表現できない文字を含む場合はエラー
+pub(crate) fn encode_sjis_cchar_slice(s: &str, dst: &mut [c_char]) -> Result<(), SjisStringError> {
+    if let Some(c) = s.chars().find(|c| *c == '\0') {
+        return Err(SjisStringError::Unmappable(c));
+    }
+
+    let (encoded, _encoding, errors) = SHIFT_JIS.encode(s);
+    if errors {
+        let c = s
+            .chars()
+            .find(|c| SHIFT_JIS.encode(c.encode_utf8(&mut [0; 4])).2)
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        return Err(SjisStringError::Unmappable(c));
+    }
+
+    if encoded.len() >= dst.len() {
+        return Err(SjisStringError::TooLong {
+            len: encoded.len(),
+            max: dst.len() - 1,
+        });
+    }
+
+    dst.fill(0);
+    for (d, s) in dst.iter_mut().zip(encoded.iter()) {
+        *d = *s as c_char;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SjisStringError {
+    Unmappable(char),
+    TooLong { len: usize, max: usize },
+}
+
+impl std::fmt::Display for SjisStringError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unmappable(c) => write!(f, "{c:?} cannot be encoded in Shift_JIS"),
+            Self::TooLong { len, max } => {
+                write!(f, "encoded string is {len} bytes long (max: {max} bytes)")
+            }
+        }
+    }
 }
 
+impl std::error::Error for SjisStringError {}
+
 pub const LEN_TEXT_BUF_MAX: u32 = 64 * 1024;
 pub const LEN_RAW_BUF_MAX_BYTES: u32 = 1024 * 1024;
 
@@ -84,6 +137,14 @@ pub enum ResultCode {
     USERDIC_NOENTRY = -1012,
 }
 
+impl std::fmt::Display for ResultCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "aitalked returned {self:?} ({})", *self as i32)
+    }
+}
+
+impl std::error::Error for ResultCode {}
+
 #[repr(i32)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
@@ -96,6 +157,16 @@ pub enum JobInOut {
     AIKANA_TO_JEITA = 32,
 }
 
+impl JobInOut {
+    /// `text_to_speech` 側のジョブか (`false` なら `text_to_kana` 側)
+    pub fn is_speech(&self) -> bool {
+        matches!(
+            self,
+            Self::PLAIN_TO_WAVE | Self::AIKANA_TO_WAVE | Self::JEITA_TO_WAVE
+        )
+    }
+}
+
 pub const MAX_VOICE_NAME: usize = 80;
 pub const MAX_JEITA_CONTROL: usize = 12;
 
@@ -134,6 +205,24 @@ impl Default for JeitaParam {
     }
 }
 
+impl JeitaParam {
+    pub fn female_name(&self) -> String {
+        decode_sjis_cchar_slice(&self.female_name)
+    }
+
+    pub fn set_female_name(&mut self, name: &str) -> Result<(), SjisStringError> {
+        encode_sjis_cchar_slice(name, &mut self.female_name)
+    }
+
+    pub fn male_name(&self) -> String {
+        decode_sjis_cchar_slice(&self.male_name)
+    }
+
+    pub fn set_male_name(&mut self, name: &str) -> Result<(), SjisStringError> {
+        encode_sjis_cchar_slice(name, &mut self.male_name)
+    }
+}
+
 #[repr(C)]
 #[derive(Derivative)]
 #[derivative(Debug, Clone)]
@@ -167,17 +256,19 @@ impl Default for SpeakerParam {
     }
 }
 
+pub type ProcTextBuf = unsafe extern "system" fn(EventReasonCode, i32, *mut c_void) -> i32;
+pub type ProcRawBuf = unsafe extern "system" fn(EventReasonCode, i32, u64, *mut c_void) -> i32;
+pub type ProcEventTts =
+    unsafe extern "system" fn(EventReasonCode, i32, u64, *const c_char, *mut c_void) -> i32;
+
 #[repr(C)]
 #[derive(Derivative)]
 #[derivative(Debug, Clone)]
 pub struct TtsParam {
     pub size: u32,
-    pub proc_text_buf: Option<unsafe extern "system" fn(EventReasonCode, i32, *mut c_void) -> i32>,
-    pub proc_raw_buf:
-        Option<unsafe extern "system" fn(EventReasonCode, i32, u64, *mut c_void) -> i32>,
-    pub proc_event_tts: Option<
-        unsafe extern "system" fn(EventReasonCode, i32, u64, *const c_char, *mut c_void) -> i32,
-    >,
+    pub proc_text_buf: Option<ProcTextBuf>,
+    pub proc_raw_buf: Option<ProcRawBuf>,
+    pub proc_event_tts: Option<ProcEventTts>,
     pub len_text_buf_bytes: u32,
     pub len_raw_buf_words: u32,
     pub volume: f32,
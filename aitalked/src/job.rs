@@ -0,0 +1,913 @@
+//! コールバックの登録・ジョブの開始・終了待ちをまとめたもの
+//!
+//! ジョブの間は `ParamScope` を保持するため、同じ DLL に対するジョブは直列に実行される
+//! (`SpeechStream` などを保持したまま同じスレッドで次のジョブを始めると `ParamError::Busy`)。
+//! `ParamScope` を保持する型は `Send` ではなく、ジョブを始めたスレッドで使い切る必要がある
+//!
+//! 変換の各段は `KanaOutput` を受け取り、次の段にそのまま渡せるようにしている
+//! (`plain_to_aikana` → `aikana_to_jeita` → `jeita_to_wave`)。外部から受け取ったカナは `str::parse` で `KanaOutput` にする
+
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::str::FromStr;
+use std::sync::mpsc;
+
+use encoding_rs::SHIFT_JIS;
+
+use crate::aikana::{AiKana, ParseError, AUTO_BOOKMARK_PREFIX};
+use crate::api::{Aitalked, ParamScope};
+use crate::binding::*;
+use crate::model::*;
+use crate::rich_text::{self, RichText};
+
+/// SJIS 文字列に変換する (NUL を含む場合はエラー)
+pub fn to_sjis_cstring(text: &str) -> Result<CString, JobError> {
+    let (bytes, _encoding, errors) = SHIFT_JIS.encode(text);
+
+    if errors {
+        let c = text
+            .chars()
+            .find(|c| SHIFT_JIS.encode(c.encode_utf8(&mut [0; 4])).2)
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        return Err(JobError::Encode(SjisStringError::Unmappable(c)));
+    }
+
+    CString::new(bytes.into_owned())
+        .map_err(|_| JobError::Encode(SjisStringError::Unmappable('\0')))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KanaOutput {
+    /// NUL を含まない SJIS 文字列
+    pub kana: Vec<u8>,
+    /// `get_kana` で受け取るごとの位置
+    pub positions: Vec<KanaPosition>,
+}
+
+/// `kana[..kana]` までの出力が、入力の SJIS 文字列上 `..source` までに当たる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KanaPosition {
+    pub kana: usize,
+    pub source: u32,
+}
+
+impl KanaOutput {
+    pub fn to_string_lossy(&self) -> String {
+        SHIFT_JIS.decode(&self.kana).0.into_owned()
+    }
+
+    /// 次のジョブの入力としてそのまま渡せる文字列
+    pub fn to_cstring(&self) -> CString {
+        CString::new(self.kana.clone()).unwrap()
+    }
+
+    pub fn aikana(&self) -> Result<AiKana, ParseError> {
+        AiKana::from_sjis(&self.kana)
+    }
+}
+
+/// ジョブを経由しないカナ (`positions` は空)
+impl FromStr for KanaOutput {
+    type Err = JobError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            kana: to_sjis_cstring(s)?.into_bytes(),
+            positions: vec![],
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpeechEvent {
+    /// `PH_LABEL`
+    Phoneme(String),
+    /// `BOOKMARK`
+    Bookmark(String),
+    /// `AUTO_BOOKMARK`: 入力テキスト上の位置
+    AutoBookmark(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedEvent {
+    pub tick: u64,
+    pub event: SpeechEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpeechChunk {
+    Audio { tick: u64, samples: Vec<i16> },
+    Event(TimedEvent),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpeechOutput {
+    pub samples: Vec<i16>,
+    pub events: Vec<TimedEvent>,
+}
+
+impl SpeechOutput {
+    pub fn push(&mut self, chunk: SpeechChunk) {
+        match chunk {
+            SpeechChunk::Audio { samples, .. } => self.samples.extend_from_slice(&samples),
+            SpeechChunk::Event(event) => self.events.push(event),
+        }
+    }
+}
+
+impl FromIterator<SpeechChunk> for SpeechOutput {
+    fn from_iter<I: IntoIterator<Item = SpeechChunk>>(iter: I) -> Self {
+        let mut output = Self::default();
+        for chunk in iter {
+            output.push(chunk);
+        }
+        output
+    }
+}
+
+struct KanaContext {
+    aitalked: Aitalked,
+    buffer: Vec<u8>,
+    positions: Vec<KanaPosition>,
+    notify: mpsc::Sender<()>,
+    len_text_buf_bytes: u32,
+}
+
+extern "system" fn kana_callback(
+    reason_code: EventReasonCode,
+    job_id: i32,
+    user_data: *mut c_void,
+) -> i32 {
+    match reason_code {
+        EventReasonCode::TEXTBUF_FULL
+        | EventReasonCode::TEXTBUF_FLUSH
+        | EventReasonCode::TEXTBUF_CLOSE => (),
+        _ => return 0,
+    }
+
+    let context = unsafe { &mut *(user_data as *mut KanaContext) };
+    let buffer_length = context.len_text_buf_bytes.min(LEN_TEXT_BUF_MAX);
+
+    let mut buffer = vec![0; buffer_length as usize];
+
+    loop {
+        let mut bytes_read = 0;
+        let mut position = 0;
+
+        let code = unsafe {
+            context
+                .aitalked
+                .get_kana(job_id, &mut buffer, &mut bytes_read, &mut position)
+        };
+
+        if code != ResultCode::SUCCESS {
+            break;
+        }
+
+        context
+            .buffer
+            .extend_from_slice(&buffer[0..bytes_read as usize]);
+        context.positions.push(KanaPosition {
+            kana: context.buffer.len(),
+            source: position,
+        });
+
+        if bytes_read < buffer_length - 1 {
+            break;
+        }
+    }
+
+    if reason_code == EventReasonCode::TEXTBUF_CLOSE {
+        let _ = context.notify.send(());
+    }
+
+    0
+}
+
+enum SpeechMessage {
+    Chunk(SpeechChunk),
+    /// `get_data` で受け取れる音声がある
+    Ready,
+    Close,
+}
+
+struct SpeechContext {
+    aitalked: Aitalked,
+    sender: mpsc::Sender<SpeechMessage>,
+    len_raw_buf_words: u32,
+}
+
+extern "system" fn raw_buf_callback(
+    reason_code: EventReasonCode,
+    job_id: i32,
+    tick: u64,
+    user_data: *mut c_void,
+) -> i32 {
+    match reason_code {
+        EventReasonCode::RAWBUF_FULL
+        | EventReasonCode::RAWBUF_FLUSH
+        | EventReasonCode::RAWBUF_CLOSE => (),
+        _ => return 0,
+    }
+
+    let context = unsafe { &mut *(user_data as *mut SpeechContext) };
+    let buffer_bytes = (context.len_raw_buf_words * 2).min(LEN_RAW_BUF_MAX_BYTES);
+
+    let mut buffer = vec![0; buffer_bytes as usize];
+    let mut samples = vec![];
+
+    loop {
+        let mut samples_read = 0;
+        let code = unsafe {
+            context
+                .aitalked
+                .get_data(job_id, &mut buffer, &mut samples_read)
+        };
+
+        if code != ResultCode::SUCCESS {
+            break;
+        }
+
+        samples.extend(
+            buffer[0..(samples_read * 2) as usize]
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]])),
+        );
+
+        if samples_read * 2 < buffer_bytes {
+            break;
+        }
+    }
+
+    if !samples.is_empty() {
+        let _ = context
+            .sender
+            .send(SpeechMessage::Chunk(SpeechChunk::Audio { tick, samples }));
+    }
+
+    if reason_code == EventReasonCode::RAWBUF_CLOSE {
+        let _ = context.sender.send(SpeechMessage::Close);
+    }
+
+    0
+}
+
+/// 音声は受け取らず、`SpeechReader` に知らせるだけ
+///
+/// エンジンは `get_data` で受け取られるまで音声を内部のバッファに保持し、次の通知でも読み残しを返す前提。
+/// そのためここでは何も読まず、読み出す側が自分のペースで `get_data` を呼ぶ
+extern "system" fn raw_buf_notify_callback(
+    reason_code: EventReasonCode,
+    _job_id: i32,
+    _tick: u64,
+    user_data: *mut c_void,
+) -> i32 {
+    let context = unsafe { &mut *(user_data as *mut SpeechContext) };
+
+    let message = match reason_code {
+        EventReasonCode::RAWBUF_FULL | EventReasonCode::RAWBUF_FLUSH => SpeechMessage::Ready,
+        EventReasonCode::RAWBUF_CLOSE => SpeechMessage::Close,
+        _ => return 0,
+    };
+    let _ = context.sender.send(message);
+
+    0
+}
+
+extern "system" fn event_callback(
+    reason_code: EventReasonCode,
+    _job_id: i32,
+    tick: u64,
+    name: *const c_char,
+    user_data: *mut c_void,
+) -> i32 {
+    let context = unsafe { &mut *(user_data as *mut SpeechContext) };
+
+    let name = match name.is_null() {
+        true => String::new(),
+        false => {
+            let name = unsafe { CStr::from_ptr(name) };
+            SHIFT_JIS.decode(name.to_bytes()).0.into_owned()
+        }
+    };
+
+    let event = match reason_code {
+        EventReasonCode::PH_LABEL => SpeechEvent::Phoneme(name),
+        EventReasonCode::BOOKMARK => SpeechEvent::Bookmark(name),
+        EventReasonCode::AUTO_BOOKMARK => {
+            match name.trim_start_matches(AUTO_BOOKMARK_PREFIX).parse() {
+                Ok(position) => SpeechEvent::AutoBookmark(position),
+                Err(_) => return 0,
+            }
+        }
+        _ => return 0,
+    };
+
+    let _ = context
+        .sender
+        .send(SpeechMessage::Chunk(SpeechChunk::Event(TimedEvent {
+            tick,
+            event,
+        })));
+
+    0
+}
+
+struct StartedSpeech {
+    job_id: i32,
+    receiver: mpsc::Receiver<SpeechMessage>,
+    context: Box<SpeechContext>,
+    scope: ParamScope,
+}
+
+/// 音声合成ジョブから届いた音声・イベントを順に返す
+///
+/// 破棄時にジョブを閉じ、パラメータを元に戻す
+pub struct SpeechStream {
+    aitalked: Aitalked,
+    job_id: i32,
+    receiver: mpsc::Receiver<SpeechMessage>,
+    closed: bool,
+    _context: Box<SpeechContext>,
+    _scope: ParamScope,
+}
+
+impl SpeechStream {
+    pub fn job_id(&self) -> i32 {
+        self.job_id
+    }
+}
+
+impl fmt::Debug for SpeechStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpeechStream")
+            .field("job_id", &self.job_id)
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+impl Iterator for SpeechStream {
+    type Item = SpeechChunk;
+
+    fn next(&mut self) -> Option<SpeechChunk> {
+        if self.closed {
+            return None;
+        }
+
+        loop {
+            match self.receiver.recv() {
+                Ok(SpeechMessage::Chunk(chunk)) => return Some(chunk),
+                Ok(SpeechMessage::Ready) => (),
+                Ok(SpeechMessage::Close) | Err(_) => {
+                    self.closed = true;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SpeechStream {
+    fn drop(&mut self) {
+        unsafe { self.aitalked.close_speech(self.job_id, 0) };
+    }
+}
+
+/// 音声合成ジョブの音声を、読み出されるたびに `get_data` で受け取る
+///
+/// `Read` ではリトルエンディアンの 16 bit PCM を、`Iterator` ではサンプルを返す。
+/// イベントは読み進めた分だけ `events` に溜まる。破棄時にジョブを閉じ、パラメータを元に戻す
+pub struct SpeechReader {
+    aitalked: Aitalked,
+    job_id: i32,
+    pcm: PcmBuffer,
+    _context: Box<SpeechContext>,
+    _scope: ParamScope,
+}
+
+impl SpeechReader {
+    pub fn job_id(&self) -> i32 {
+        self.job_id
+    }
+
+    /// ここまでに届いたイベント
+    pub fn events(&self) -> &[TimedEvent] {
+        &self.pcm.events
+    }
+
+    pub fn take_events(&mut self) -> Vec<TimedEvent> {
+        std::mem::take(&mut self.pcm.events)
+    }
+
+    /// `PcmBuffer` に渡す、このジョブの `get_data`
+    fn get_data(&self) -> impl FnMut(&mut [u8], &mut u32) -> ResultCode {
+        let (aitalked, job_id) = (self.aitalked, self.job_id);
+        move |buffer, words_read| unsafe { aitalked.get_data(job_id, buffer, words_read) }
+    }
+}
+
+/// `SpeechReader` の読み出し位置と、コールバックから届いた通知
+///
+/// `get_data` を引数で受け取るのは、エンジン無しで読み出しを試せるようにするため
+struct PcmBuffer {
+    receiver: mpsc::Receiver<SpeechMessage>,
+    buffer: Vec<u8>,
+    offset: usize,
+    filled: usize,
+    events: Vec<TimedEvent>,
+    /// `get_data` を呼んでよいか
+    ready: bool,
+    closed: bool,
+}
+
+impl PcmBuffer {
+    fn new(receiver: mpsc::Receiver<SpeechMessage>, buffer_bytes: usize) -> Self {
+        Self {
+            receiver,
+            buffer: vec![0; buffer_bytes],
+            offset: 0,
+            filled: 0,
+            events: vec![],
+            ready: false,
+            closed: false,
+        }
+    }
+
+    /// `buffer` が空なら次の音声を受け取る。終わりに達したら `false`
+    fn fill(&mut self, mut get_data: impl FnMut(&mut [u8], &mut u32) -> ResultCode) -> bool {
+        if self.offset < self.filled {
+            return true;
+        }
+
+        loop {
+            if self.ready {
+                let mut samples_read = 0;
+                let code = get_data(&mut self.buffer, &mut samples_read);
+
+                let bytes = (samples_read * 2) as usize;
+                if code == ResultCode::SUCCESS && bytes > 0 {
+                    // 一杯まで読めなければ、次の通知まで待つ
+                    self.ready = bytes == self.buffer.len();
+                    self.offset = 0;
+                    self.filled = bytes;
+                    return true;
+                }
+                self.ready = false;
+            }
+
+            if self.closed {
+                return false;
+            }
+
+            match self.receiver.recv() {
+                Ok(SpeechMessage::Chunk(SpeechChunk::Event(event))) => self.events.push(event),
+                Ok(SpeechMessage::Chunk(SpeechChunk::Audio { .. })) => (),
+                Ok(SpeechMessage::Ready) => self.ready = true,
+                Ok(SpeechMessage::Close) | Err(_) => {
+                    self.ready = true;
+                    self.closed = true;
+                }
+            }
+        }
+    }
+
+    fn fill_buf(&mut self, get_data: impl FnMut(&mut [u8], &mut u32) -> ResultCode) -> &[u8] {
+        match self.fill(get_data) {
+            true => &self.buffer[self.offset..self.filled],
+            false => &[],
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.offset = (self.offset + amt).min(self.filled);
+    }
+
+    fn read(
+        &mut self,
+        buf: &mut [u8],
+        get_data: impl FnMut(&mut [u8], &mut u32) -> ResultCode,
+    ) -> usize {
+        let available = self.fill_buf(get_data);
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        len
+    }
+
+    fn next_sample(
+        &mut self,
+        get_data: impl FnMut(&mut [u8], &mut u32) -> ResultCode,
+    ) -> Option<i16> {
+        // `Read` で奇数バイトだけ読まれていた場合は、読みかけのサンプルの残りの1バイトを捨てる
+        // (`buffer` は常にサンプルの境界から始まり、`filled` は偶数)
+        if self.offset % 2 == 1 {
+            self.offset += 1;
+        }
+
+        if !self.fill(get_data) {
+            return None;
+        }
+
+        let sample = i16::from_le_bytes([self.buffer[self.offset], self.buffer[self.offset + 1]]);
+        self.offset += 2;
+        Some(sample)
+    }
+}
+
+impl fmt::Debug for SpeechReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpeechReader")
+            .field("job_id", &self.job_id)
+            .field("closed", &self.pcm.closed)
+            .finish()
+    }
+}
+
+impl Read for SpeechReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let get_data = self.get_data();
+        Ok(self.pcm.read(buf, get_data))
+    }
+}
+
+impl BufRead for SpeechReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let get_data = self.get_data();
+        Ok(self.pcm.fill_buf(get_data))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pcm.consume(amt);
+    }
+}
+
+impl Iterator for SpeechReader {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let get_data = self.get_data();
+        self.pcm.next_sample(get_data)
+    }
+}
+
+impl Drop for SpeechReader {
+    fn drop(&mut self) {
+        unsafe { self.aitalked.close_speech(self.job_id, 0) };
+    }
+}
+
+impl Aitalked {
+    /// `PLAIN_TO_AIKANA` または `AIKANA_TO_JEITA` のジョブを実行し、結果を待つ
+    ///
+    /// タイムアウトは無く、エンジンが `TEXTBUF_CLOSE` を通知するまで呼び出したスレッドをブロックする。
+    /// 開始に成功したジョブは必ず `TEXTBUF_CLOSE` まで進む前提 (`SpeechStream` の `RAWBUF_CLOSE` と同じ)
+    pub unsafe fn kana_job(
+        &self,
+        mode: JobInOut,
+        text: &CStr,
+        overrides: &ParamOverrides,
+    ) -> Result<KanaOutput, JobError> {
+        if mode.is_speech() {
+            return Err(JobError::UnsupportedMode(mode));
+        }
+
+        let overrides = ParamOverrides {
+            proc_text_buf: Some(kana_callback),
+            ..overrides.clone()
+        };
+        let scope = self.param_scope(&overrides)?;
+
+        let (notify, receiver) = mpsc::channel();
+        let mut context = Box::new(KanaContext {
+            aitalked: *self,
+            buffer: vec![],
+            positions: vec![],
+            notify,
+            len_text_buf_bytes: scope.tts_param().len_text_buf_bytes,
+        });
+
+        let mut job_id = 0;
+        let code = self.text_to_kana_with_mode(
+            &mut job_id,
+            &mut *context as *mut KanaContext as *mut c_void,
+            text,
+            mode,
+        );
+
+        if code != ResultCode::SUCCESS {
+            return Err(code.into());
+        }
+
+        let _ = receiver.recv();
+        self.close_kana(job_id, 0);
+        scope.restore()?;
+
+        Ok(KanaOutput {
+            kana: std::mem::take(&mut context.buffer),
+            positions: std::mem::take(&mut context.positions),
+        })
+    }
+
+    /// `raw_buf` をコールバックにして `*_TO_WAVE` のジョブを開始する
+    unsafe fn start_speech(
+        &self,
+        mode: JobInOut,
+        text: &CStr,
+        overrides: &ParamOverrides,
+        raw_buf: ProcRawBuf,
+    ) -> Result<StartedSpeech, JobError> {
+        if !mode.is_speech() {
+            return Err(JobError::UnsupportedMode(mode));
+        }
+
+        let overrides = ParamOverrides {
+            proc_raw_buf: Some(raw_buf),
+            proc_event_tts: Some(event_callback),
+            ..overrides.clone()
+        };
+        let scope = self.param_scope(&overrides)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let mut context = Box::new(SpeechContext {
+            aitalked: *self,
+            sender,
+            len_raw_buf_words: scope.tts_param().len_raw_buf_words,
+        });
+
+        let mut job_id = 0;
+        let code = self.text_to_speech_with_mode(
+            &mut job_id,
+            &mut *context as *mut SpeechContext as *mut c_void,
+            text,
+            mode,
+        );
+
+        if code != ResultCode::SUCCESS {
+            return Err(code.into());
+        }
+
+        Ok(StartedSpeech {
+            job_id,
+            receiver,
+            context,
+            scope,
+        })
+    }
+
+    /// `*_TO_WAVE` のジョブを開始する
+    pub unsafe fn speech_stream(
+        &self,
+        mode: JobInOut,
+        text: &CStr,
+        overrides: &ParamOverrides,
+    ) -> Result<SpeechStream, JobError> {
+        let started = self.start_speech(mode, text, overrides, raw_buf_callback)?;
+
+        Ok(SpeechStream {
+            aitalked: *self,
+            job_id: started.job_id,
+            receiver: started.receiver,
+            closed: false,
+            _context: started.context,
+            _scope: started.scope,
+        })
+    }
+
+    /// `*_TO_WAVE` のジョブを開始し、音声を読み出す側から `get_data` で受け取る
+    pub unsafe fn speech_reader(
+        &self,
+        mode: JobInOut,
+        text: &CStr,
+        overrides: &ParamOverrides,
+    ) -> Result<SpeechReader, JobError> {
+        let started = self.start_speech(mode, text, overrides, raw_buf_notify_callback)?;
+        let buffer_bytes = (started.context.len_raw_buf_words * 2).min(LEN_RAW_BUF_MAX_BYTES);
+
+        Ok(SpeechReader {
+            aitalked: *self,
+            job_id: started.job_id,
+            pcm: PcmBuffer::new(started.receiver, buffer_bytes as usize),
+            _context: started.context,
+            _scope: started.scope,
+        })
+    }
+
+    /// `*_TO_WAVE` のジョブを実行し、音声とイベントを全て受け取る
+    pub unsafe fn speech_job(
+        &self,
+        mode: JobInOut,
+        text: &CStr,
+        overrides: &ParamOverrides,
+    ) -> Result<SpeechOutput, JobError> {
+        Ok(self.speech_stream(mode, text, overrides)?.collect())
+    }
+
+    /// 平文から1つのジョブで直接合成する (`text_to_kana` を経由しない)
+    pub unsafe fn plain_to_wave(
+        &self,
+        text: &str,
+        overrides: &ParamOverrides,
+    ) -> Result<SpeechOutput, JobError> {
+        self.speech_job(JobInOut::PLAIN_TO_WAVE, &to_sjis_cstring(text)?, overrides)
+    }
+
+    pub unsafe fn plain_to_wave_stream(
+        &self,
+        text: &str,
+        overrides: &ParamOverrides,
+    ) -> Result<SpeechStream, JobError> {
+        self.speech_stream(JobInOut::PLAIN_TO_WAVE, &to_sjis_cstring(text)?, overrides)
+    }
+
+    pub unsafe fn plain_to_wave_reader(
+        &self,
+        text: &str,
+        overrides: &ParamOverrides,
+    ) -> Result<SpeechReader, JobError> {
+        self.speech_reader(JobInOut::PLAIN_TO_WAVE, &to_sjis_cstring(text)?, overrides)
+    }
+
+    pub unsafe fn plain_to_aikana(
+        &self,
+        text: &str,
+        overrides: &ParamOverrides,
+    ) -> Result<KanaOutput, JobError> {
+        self.kana_job(
+            JobInOut::PLAIN_TO_AIKANA,
+            &to_sjis_cstring(text)?,
+            overrides,
+        )
+    }
+
+    /// `extend_format` に `JEITA_RUBY` を加えて読み指定付きテキストを変換する
+    pub unsafe fn rich_text_to_aikana(
+        &self,
+        text: &RichText,
+        overrides: &ParamOverrides,
+    ) -> Result<KanaOutput, JobError> {
+        let extend_format = match overrides.extend_format {
+            Some(format) => format,
+            None => {
+                self.get_boxed_param()
+                    .map_err(JobError::Engine)?
+                    .tts_param()
+                    .extend_format
+            }
+        };
+
+        let overrides = ParamOverrides {
+            extend_format: Some(extend_format | rich_text::EXTEND_FORMAT),
+            ..overrides.clone()
+        };
+
+        self.kana_job(JobInOut::PLAIN_TO_AIKANA, &text.to_cstring()?, &overrides)
+    }
+
+    /// AIKana を JEITA IT-4002 形式のカナに変換する
+    pub unsafe fn aikana_to_jeita(
+        &self,
+        kana: &KanaOutput,
+        overrides: &ParamOverrides,
+    ) -> Result<KanaOutput, JobError> {
+        self.kana_job(JobInOut::AIKANA_TO_JEITA, &kana.to_cstring(), overrides)
+    }
+
+    pub unsafe fn aikana_to_wave(
+        &self,
+        kana: &KanaOutput,
+        overrides: &ParamOverrides,
+    ) -> Result<SpeechOutput, JobError> {
+        self.speech_job(JobInOut::AIKANA_TO_WAVE, &kana.to_cstring(), overrides)
+    }
+
+    /// JEITA IT-4002 形式のカナから直接合成する
+    pub unsafe fn jeita_to_wave(
+        &self,
+        jeita: &KanaOutput,
+        overrides: &ParamOverrides,
+    ) -> Result<SpeechOutput, JobError> {
+        self.speech_job(JobInOut::JEITA_TO_WAVE, &jeita.to_cstring(), overrides)
+    }
+}
+
+#[derive(Debug)]
+pub enum JobError {
+    Engine(ResultCode),
+    Param(ParamError),
+    Encode(SjisStringError),
+    UnsupportedMode(JobInOut),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Engine(code) => write!(f, "{code}"),
+            Self::Param(e) => write!(f, "{e}"),
+            Self::Encode(e) => write!(f, "{e}"),
+            Self::UnsupportedMode(mode) => write!(f, "{mode:?} is not supported by this job"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+impl From<ResultCode> for JobError {
+    fn from(code: ResultCode) -> Self {
+        Self::Engine(code)
+    }
+}
+
+impl From<ParamError> for JobError {
+    fn from(e: ParamError) -> Self {
+        Self::Param(e)
+    }
+}
+
+impl From<SjisStringError> for JobError {
+    fn from(e: SjisStringError) -> Self {
+        Self::Encode(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_kana_for_the_next_job() {
+        let kana: KanaOutput = "ｱｲ'ｳ".parse().unwrap();
+        assert_eq!(kana.kana, b"\xb1\xb2'\xb3");
+        assert!(kana.positions.is_empty());
+        assert_eq!(kana.to_string_lossy(), "ｱｲ'ｳ");
+
+        assert!("ア\0".parse::<KanaOutput>().is_err());
+    }
+
+    /// `samples` を `get_data` で少しずつ返すジョブ。通知は先に全て送っておく
+    fn fake_job(samples: &[i16]) -> (PcmBuffer, impl FnMut(&mut [u8], &mut u32) -> ResultCode) {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(SpeechMessage::Ready).unwrap();
+        sender
+            .send(SpeechMessage::Chunk(SpeechChunk::Event(TimedEvent {
+                tick: 0,
+                event: SpeechEvent::Bookmark("a".to_owned()),
+            })))
+            .unwrap();
+        sender.send(SpeechMessage::Ready).unwrap();
+        sender.send(SpeechMessage::Close).unwrap();
+
+        let mut remaining = samples.to_vec();
+        let get_data = move |buffer: &mut [u8], words_read: &mut u32| {
+            let len = (buffer.len() / 2).min(remaining.len());
+            for (bytes, sample) in buffer.chunks_exact_mut(2).zip(remaining.drain(..len)) {
+                bytes.copy_from_slice(&sample.to_le_bytes());
+            }
+            *words_read = len as u32;
+            ResultCode::SUCCESS
+        };
+
+        (PcmBuffer::new(receiver, 8), get_data)
+    }
+
+    #[test]
+    fn read_and_iterator_agree() {
+        let samples: Vec<i16> = (0..21).map(|i| i * 1000 - 10000).collect();
+        let expected: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+
+        let (mut pcm, mut get_data) = fake_job(&samples);
+        let iterated: Vec<u8> = std::iter::from_fn(|| pcm.next_sample(&mut get_data))
+            .flat_map(i16::to_le_bytes)
+            .collect();
+        assert_eq!(iterated, expected);
+        assert_eq!(pcm.events.len(), 1);
+
+        // 3 バイトずつ読むと、サンプルの途中で区切られる
+        let (mut pcm, mut get_data) = fake_job(&samples);
+        let mut read = vec![];
+        let mut buf = [0; 3];
+        loop {
+            let len = pcm.read(&mut buf, &mut get_data);
+            if len == 0 {
+                break;
+            }
+            assert!(len <= 3);
+            read.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn iterator_skips_half_read_sample() {
+        let samples: Vec<i16> = (0..21).collect();
+        let (mut pcm, mut get_data) = fake_job(&samples);
+
+        let mut buf = [0; 3];
+        assert_eq!(pcm.read(&mut buf, &mut get_data), 3);
+        assert_eq!(buf, [0, 0, 1]);
+
+        let rest: Vec<_> = std::iter::from_fn(|| pcm.next_sample(&mut get_data)).collect();
+        assert_eq!(rest, samples[2..]);
+    }
+}
@@ -0,0 +1,173 @@
+//! 合成時のイベントのタイミングをラベルファイルに書き出す
+//!
+//! 音素名はエンジンが通知したものをそのまま使う
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::job::SpeechEvent;
+use crate::source_map::SourceMap;
+use crate::subtitle::{self, Granularity};
+use crate::timeline::{Timeline, Track, TICKS_PER_SECOND};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LabelFormat {
+    /// `開始 終了 音素` (100 ns 単位の整数)
+    #[default]
+    Htk,
+    /// `開始 終了 音素` (秒単位、小数点以下7桁) 。Julius の segmentation-kit が出力する形式
+    Julius,
+}
+
+/// 100 ns 単位
+pub fn tick_to_htk(tick: u64) -> u64 {
+    tick * 10_000_000 / TICKS_PER_SECOND
+}
+
+pub fn write_labels<W: Write>(
+    mut writer: W,
+    timeline: &Timeline,
+    format: LabelFormat,
+) -> io::Result<()> {
+    for segment in timeline.track(Track::Phoneme) {
+        let SpeechEvent::Phoneme(name) = &segment.event else {
+            continue;
+        };
+        let name = name.trim();
+
+        match format {
+            LabelFormat::Htk => writeln!(
+                writer,
+                "{} {} {name}",
+                tick_to_htk(segment.start),
+                tick_to_htk(segment.end)
+            )?,
+            LabelFormat::Julius => writeln!(
+                writer,
+                "{:.7} {:.7} {name}",
+                segment.start as f64 / TICKS_PER_SECOND as f64,
+                segment.end as f64 / TICKS_PER_SECOND as f64
+            )?,
+        }
+    }
+
+    Ok(())
+}
+
+pub fn labels_to_string(timeline: &Timeline, format: LabelFormat) -> String {
+    let mut buffer = vec![];
+    write_labels(&mut buffer, timeline, format).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// 音声ファイルと同じ名前の `.lab`
+pub fn label_path(audio: impl AsRef<Path>) -> PathBuf {
+    audio.as_ref().with_extension("lab")
+}
+
+/// 音声ファイルの隣に `.lab` を書き出し、そのパスを返す
+pub fn save_labels(
+    audio: impl AsRef<Path>,
+    timeline: &Timeline,
+    format: LabelFormat,
+) -> io::Result<PathBuf> {
+    let path = label_path(audio);
+    let mut writer = BufWriter::new(File::create(&path)?);
+    write_labels(&mut writer, timeline, format)?;
+    writer.flush()?;
+    Ok(path)
+}
+
+/// Audacity のラベルトラック1つ分の行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudacityLabel {
+    /// tick
+    pub start: u64,
+    /// tick (`start` と同じなら位置だけのラベル)
+    pub end: u64,
+    pub text: String,
+}
+
+/// 文ごとの範囲。`source` はジョブに渡した文字列か `RichText`
+pub fn sentence_labels(source: impl Into<SourceMap>, timeline: &Timeline) -> Vec<AudacityLabel> {
+    subtitle::cues(source, timeline, Granularity::Sentence)
+        .into_iter()
+        .map(|cue| AudacityLabel {
+            start: cue.start,
+            end: cue.end,
+            text: cue.text,
+        })
+        .collect()
+}
+
+/// `BOOKMARK` の位置
+pub fn bookmark_labels(timeline: &Timeline) -> Vec<AudacityLabel> {
+    timeline
+        .track(Track::Bookmark)
+        .filter_map(|s| match &s.event {
+            SpeechEvent::Bookmark(mark) => Some(AudacityLabel {
+                start: s.start,
+                end: s.start,
+                text: mark.clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `AUTO_BOOKMARK` で区切った句ごとの範囲。`source` はジョブに渡した文字列か `RichText`
+pub fn phrase_labels(source: impl Into<SourceMap>, timeline: &Timeline) -> Vec<AudacityLabel> {
+    let map = source.into();
+    let text = map.text();
+    let phrases: Vec<_> = timeline
+        .track(Track::AutoBookmark)
+        .filter_map(|s| match s.event {
+            SpeechEvent::AutoBookmark(position) => Some((s, position as usize)),
+            _ => None,
+        })
+        .collect();
+
+    phrases
+        .iter()
+        .enumerate()
+        .map(|(i, &(segment, position))| {
+            let next = phrases
+                .get(i + 1)
+                .map(|(_, next)| *next)
+                .unwrap_or(map.sjis_len());
+
+            AudacityLabel {
+                start: segment.start,
+                end: segment.end,
+                text: text[map.byte_range(position..next.max(position))]
+                    .trim()
+                    .to_owned(),
+            }
+        })
+        .collect()
+}
+
+/// `開始秒\t終了秒\tラベル` (「ファイル > 取り込み > ラベル」で読み込める形式)
+pub fn write_audacity_labels<W: Write>(mut writer: W, labels: &[AudacityLabel]) -> io::Result<()> {
+    let mut labels: Vec<_> = labels.iter().collect();
+    labels.sort_by_key(|l| (l.start, l.end));
+
+    for label in labels {
+        let text = label.text.replace(['\t', '\r', '\n'], " ");
+        writeln!(
+            writer,
+            "{:.6}\t{:.6}\t{text}",
+            label.start as f64 / TICKS_PER_SECOND as f64,
+            label.end as f64 / TICKS_PER_SECOND as f64
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn audacity_labels_to_string(labels: &[AudacityLabel]) -> String {
+    let mut buffer = vec![];
+    write_audacity_labels(&mut buffer, labels).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
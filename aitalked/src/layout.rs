@@ -0,0 +1,187 @@
+//! `repr(C)` 構造体のサイズ・オフセットの自己検査
+//!
+//! 期待値は i686 (aitalked.dll) のもの。i686 向けのビルドではコンパイル時にも検査する
+
+use std::fmt;
+use std::mem::{offset_of, size_of};
+
+use crate::api::Aitalked;
+use crate::binding::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub ty: &'static str,
+    pub field: Option<&'static str>,
+    pub expected: usize,
+    pub actual: usize,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.field {
+            Some(field) => write!(f, "offset of {}::{field}", self.ty)?,
+            None => write!(f, "size of {}", self.ty)?,
+        }
+        write!(f, ": expected {}, actual {}", self.expected, self.actual)
+    }
+}
+
+macro_rules! size {
+    ($ty:ty, $expected:expr) => {
+        Layout {
+            ty: stringify!($ty),
+            field: None,
+            expected: $expected,
+            actual: size_of::<$ty>(),
+        }
+    };
+}
+
+macro_rules! offset {
+    ($ty:ty, $field:ident, $expected:expr) => {
+        Layout {
+            ty: stringify!($ty),
+            field: Some(stringify!($field)),
+            expected: $expected,
+            actual: offset_of!($ty, $field),
+        }
+    };
+}
+
+pub const TTS_PARAM_SIZE: usize = 312;
+pub const SPEAKER_PARAM_SIZE: usize = 188;
+
+pub const LAYOUTS: &[Layout] = &[
+    size!(JobParam, 8),
+    offset!(JobParam, model_in_out, 0),
+    offset!(JobParam, user_data, 4),
+    size!(JeitaParam, 184),
+    offset!(JeitaParam, female_name, 0),
+    offset!(JeitaParam, male_name, 80),
+    offset!(JeitaParam, pause_middle, 160),
+    offset!(JeitaParam, pause_long, 164),
+    offset!(JeitaParam, pause_sentence, 168),
+    offset!(JeitaParam, control, 172),
+    size!(SpeakerParam, SPEAKER_PARAM_SIZE),
+    offset!(SpeakerParam, voice_name, 0),
+    offset!(SpeakerParam, volume, 80),
+    offset!(SpeakerParam, speed, 84),
+    offset!(SpeakerParam, pitch, 88),
+    offset!(SpeakerParam, range, 92),
+    offset!(SpeakerParam, pause_middle, 96),
+    offset!(SpeakerParam, pause_long, 100),
+    offset!(SpeakerParam, pause_sentence, 104),
+    offset!(SpeakerParam, style_rate, 108),
+    size!(TtsParam, TTS_PARAM_SIZE),
+    offset!(TtsParam, size, 0),
+    offset!(TtsParam, proc_text_buf, 4),
+    offset!(TtsParam, proc_raw_buf, 8),
+    offset!(TtsParam, proc_event_tts, 12),
+    offset!(TtsParam, len_text_buf_bytes, 16),
+    offset!(TtsParam, len_raw_buf_words, 20),
+    offset!(TtsParam, volume, 24),
+    offset!(TtsParam, pause_begin, 28),
+    offset!(TtsParam, pause_term, 32),
+    offset!(TtsParam, extend_format, 36),
+    offset!(TtsParam, voice_name, 40),
+    offset!(TtsParam, jeita, 120),
+    offset!(TtsParam, num_speakers, 304),
+    offset!(TtsParam, _reserved, 308),
+    offset!(TtsParam, speakers, 312),
+    size!(AitalkedConfig, 24),
+    offset!(AitalkedConfig, hz_voice_db, 0),
+    offset!(AitalkedConfig, dir_voice_dbs, 4),
+    offset!(AitalkedConfig, msec_timeout, 8),
+    offset!(AitalkedConfig, path_license, 12),
+    offset!(AitalkedConfig, code_auth_seed, 16),
+    offset!(AitalkedConfig, len_auth_seed, 20),
+];
+
+#[cfg(target_arch = "x86")]
+const _: () = {
+    let mut i = 0;
+    while i < LAYOUTS.len() {
+        assert!(
+            LAYOUTS[i].expected == LAYOUTS[i].actual,
+            "repr(C) layout does not match aitalked.dll"
+        );
+        i += 1;
+    }
+};
+
+/// 期待値と一致しないものを全て返す
+pub fn verify() -> Result<(), LayoutError> {
+    let mismatches: Vec<_> = LAYOUTS
+        .iter()
+        .filter(|l| l.expected != l.actual)
+        .copied()
+        .collect();
+
+    if !mismatches.is_empty() {
+        return Err(LayoutError::Mismatch(mismatches));
+    }
+
+    Ok(())
+}
+
+/// `get_param` が報告するサイズが `TtsParam` + `SpeakerParam` * n で割り切れるかを検査し、話者数を返す
+pub unsafe fn verify_engine(aitalked: &Aitalked) -> Result<usize, LayoutError> {
+    verify()?;
+
+    let mut size = 0;
+
+    match aitalked.get_param(std::ptr::null_mut(), &mut size) {
+        ResultCode::SUCCESS | ResultCode::INSUFFICIENT => (),
+        code => return Err(LayoutError::Engine(code)),
+    }
+
+    let header = size_of::<TtsParam>();
+    let speaker = size_of::<SpeakerParam>();
+    let reported = size as usize;
+
+    if reported < header || !(reported - header).is_multiple_of(speaker) {
+        return Err(LayoutError::UnexpectedParamSize {
+            reported,
+            header,
+            speaker,
+        });
+    }
+
+    Ok((reported - header) / speaker)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutError {
+    Mismatch(Vec<Layout>),
+    Engine(ResultCode),
+    UnexpectedParamSize {
+        reported: usize,
+        header: usize,
+        speaker: usize,
+    },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mismatch(mismatches) => {
+                write!(f, "repr(C) layout does not match aitalked.dll")?;
+                for mismatch in mismatches {
+                    write!(f, "\n - {mismatch}")?;
+                }
+                Ok(())
+            }
+            Self::Engine(code) => write!(f, "{code}"),
+            Self::UnexpectedParamSize {
+                reported,
+                header,
+                speaker,
+            } => write!(
+                f,
+                "TtsParam size reported by aitalked ({reported}) is not {header} + {speaker} * n"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
@@ -4,9 +4,24 @@ use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
+pub mod aikana;
+pub mod alignment;
 pub mod api;
+pub mod audio;
 pub mod binding;
+pub mod job;
+pub mod label;
+pub mod layout;
 pub mod model;
+pub mod preset;
+pub mod rich_text;
+pub mod source_map;
+pub mod style;
+pub mod subtitle;
+pub mod timeline;
+pub mod viseme;
+#[cfg(feature = "voiceroid2")]
+pub mod voiceroid2;
 
 pub use libloading;
 
@@ -1,6 +1,8 @@
 use std::alloc::{alloc, dealloc, Layout};
+use std::fmt;
 
 use crate::binding::*;
+use crate::preset::{PresetError, VoicePreset};
 
 #[derive(Debug)]
 pub struct BoxedTtsParam {
@@ -62,8 +64,116 @@ impl BoxedTtsParam {
     }
 }
 
+impl Clone for BoxedTtsParam {
+    fn clone(&self) -> Self {
+        let ptr = unsafe { alloc(self.layout) as *mut TtsParam };
+        if ptr.is_null() {
+            panic!("Allocation failed");
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.ptr as *const u8, ptr as *mut u8, self.layout.size())
+        };
+
+        Self {
+            ptr,
+            layout: self.layout,
+        }
+    }
+}
+
 impl Drop for BoxedTtsParam {
     fn drop(&mut self) {
         unsafe { dealloc(self.ptr as *mut u8, self.layout) }
     }
 }
+
+/// ジョブ単位で一時的に上書きするパラメータ (`None` の項目は現在の値のまま)
+#[derive(Debug, Clone, Default)]
+pub struct ParamOverrides {
+    pub proc_text_buf: Option<ProcTextBuf>,
+    pub proc_raw_buf: Option<ProcRawBuf>,
+    pub proc_event_tts: Option<ProcEventTts>,
+    pub volume: Option<f32>,
+    pub pause_begin: Option<i32>,
+    pub pause_term: Option<i32>,
+    pub extend_format: Option<ExtendFormat>,
+    pub jeita: Option<JeitaParam>,
+    pub voice: Option<VoicePreset>,
+}
+
+impl ParamOverrides {
+    pub fn apply_to(&self, param: &mut BoxedTtsParam) -> Result<(), ParamError> {
+        if let Some(voice) = &self.voice {
+            param.apply_preset(voice)?;
+        }
+
+        let tts_param = param.tts_param_mut();
+
+        if let Some(proc_text_buf) = self.proc_text_buf {
+            tts_param.proc_text_buf = Some(proc_text_buf);
+        }
+
+        if let Some(proc_raw_buf) = self.proc_raw_buf {
+            tts_param.proc_raw_buf = Some(proc_raw_buf);
+        }
+
+        if let Some(proc_event_tts) = self.proc_event_tts {
+            tts_param.proc_event_tts = Some(proc_event_tts);
+        }
+
+        if let Some(volume) = self.volume {
+            tts_param.volume = volume;
+        }
+
+        if let Some(pause_begin) = self.pause_begin {
+            tts_param.pause_begin = pause_begin;
+        }
+
+        if let Some(pause_term) = self.pause_term {
+            tts_param.pause_term = pause_term;
+        }
+
+        if let Some(extend_format) = self.extend_format {
+            tts_param.extend_format = extend_format;
+        }
+
+        if let Some(jeita) = &self.jeita {
+            tts_param.jeita = jeita.clone();
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ParamError {
+    Engine(ResultCode),
+    Preset(PresetError),
+    /// 同じスレッドで既に `ParamScope` が有効
+    Busy,
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Engine(code) => write!(f, "{code}"),
+            Self::Preset(e) => write!(f, "{e}"),
+            Self::Busy => write!(f, "parameters are already in use on this thread"),
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+impl From<ResultCode> for ParamError {
+    fn from(code: ResultCode) -> Self {
+        Self::Engine(code)
+    }
+}
+
+impl From<PresetError> for ParamError {
+    fn from(e: PresetError) -> Self {
+        Self::Preset(e)
+    }
+}
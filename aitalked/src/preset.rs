@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(any(feature = "toml", feature = "json"))]
+use std::path::Path;
+
+use crate::binding::*;
+use crate::model::BoxedTtsParam;
+use crate::style::{StyleRateError, StyleRates};
+
+/// `TtsParam` のうちボイスに依存しない設定
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EnginePreset {
+    pub volume: f32,
+    pub pause_begin: i32,
+    pub pause_term: i32,
+}
+
+impl EnginePreset {
+    pub fn from_tts_param(param: &TtsParam) -> Self {
+        Self {
+            volume: param.volume,
+            pause_begin: param.pause_begin,
+            pause_term: param.pause_term,
+        }
+    }
+
+    pub fn apply_to(&self, param: &mut TtsParam) {
+        param.volume = self.volume;
+        param.pause_begin = self.pause_begin;
+        param.pause_term = self.pause_term;
+    }
+}
+
+/// `SpeakerParam` の韻律・声質設定
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VoicePreset {
+    pub voice_name: String,
+    pub volume: f32,
+    pub speed: f32,
+    pub pitch: f32,
+    pub range: f32,
+    pub pause_middle: i32,
+    pub pause_long: i32,
+    pub pause_sentence: i32,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "StyleRates::is_empty")
+    )]
+    pub styles: StyleRates,
+}
+
+impl VoicePreset {
+    pub fn from_speaker(speaker: &SpeakerParam) -> Result<Self, PresetError> {
+        Ok(Self {
+            voice_name: decode_sjis_cchar_slice(&speaker.voice_name),
+            volume: speaker.volume,
+            speed: speaker.speed,
+            pitch: speaker.pitch,
+            range: speaker.range,
+            pause_middle: speaker.pause_middle,
+            pause_long: speaker.pause_long,
+            pause_sentence: speaker.pause_sentence,
+            styles: speaker.style_rates()?,
+        })
+    }
+
+    /// `voice_name` 以外を書き込む
+    pub fn apply_to(&self, speaker: &mut SpeakerParam) -> Result<(), PresetError> {
+        speaker.set_style_rates(&self.styles)?;
+        speaker.volume = self.volume;
+        speaker.speed = self.speed;
+        speaker.pitch = self.pitch;
+        speaker.range = self.range;
+        speaker.pause_middle = self.pause_middle;
+        speaker.pause_long = self.pause_long;
+        speaker.pause_sentence = self.pause_sentence;
+        Ok(())
+    }
+}
+
+impl BoxedTtsParam {
+    /// `voice_name` が一致する話者にプリセットを適用し、そのボイスを選択する
+    pub fn apply_preset(&mut self, preset: &VoicePreset) -> Result<(), PresetError> {
+        let speaker = self
+            .speakers_mut()
+            .iter_mut()
+            .find(|s| decode_sjis_cchar_slice(&s.voice_name) == preset.voice_name)
+            .ok_or_else(|| PresetError::VoiceNotLoaded(preset.voice_name.clone()))?;
+
+        preset.apply_to(speaker)?;
+
+        encode_sjis_cchar_slice(&preset.voice_name, &mut self.tts_param_mut().voice_name)?;
+
+        Ok(())
+    }
+
+    pub fn voice_presets(&self) -> Result<Vec<VoicePreset>, PresetError> {
+        self.speakers()
+            .iter()
+            .map(VoicePreset::from_speaker)
+            .collect()
+    }
+}
+
+/// 名前付きプリセットの集合
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PresetLibrary {
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub engine: Option<EnginePreset>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub presets: BTreeMap<String, VoicePreset>,
+}
+
+impl PresetLibrary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&VoicePreset> {
+        self.presets.get(name)
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, preset: VoicePreset) -> Option<VoicePreset> {
+        self.presets.insert(name.into(), preset)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<VoicePreset> {
+        self.presets.remove(name)
+    }
+
+    /// `engine` があれば `TtsParam` にも適用する
+    pub fn apply(&self, name: &str, param: &mut BoxedTtsParam) -> Result<(), PresetError> {
+        let preset = self
+            .get(name)
+            .ok_or_else(|| PresetError::PresetNotFound(name.to_owned()))?;
+
+        param.apply_preset(preset)?;
+
+        if let Some(engine) = &self.engine {
+            engine.apply_to(param.tts_param_mut());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_toml_str(s: &str) -> Result<Self, PresetError> {
+        toml::from_str(s).map_err(|e| PresetError::Format(e.to_string()))
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn to_toml_string(&self) -> Result<String, PresetError> {
+        toml::to_string_pretty(self).map_err(|e| PresetError::Format(e.to_string()))
+    }
+
+    #[cfg(feature = "json")]
+    pub fn from_json_str(s: &str) -> Result<Self, PresetError> {
+        serde_json::from_str(s).map_err(|e| PresetError::Format(e.to_string()))
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json_string(&self) -> Result<String, PresetError> {
+        serde_json::to_string_pretty(self).map_err(|e| PresetError::Format(e.to_string()))
+    }
+
+    /// 拡張子 (`.toml` / `.json`) で形式を判別する
+    #[cfg(any(feature = "toml", feature = "json"))]
+    pub fn load(path: &Path) -> Result<Self, PresetError> {
+        match path.extension().and_then(|e| e.to_str()) {
+            #[cfg(feature = "toml")]
+            Some("toml") => Self::from_toml_str(&std::fs::read_to_string(path)?),
+            #[cfg(feature = "json")]
+            Some("json") => Self::from_json_str(&std::fs::read_to_string(path)?),
+            _ => Err(PresetError::UnknownFormat(path.to_owned())),
+        }
+    }
+
+    /// 拡張子 (`.toml` / `.json`) で形式を判別する
+    #[cfg(any(feature = "toml", feature = "json"))]
+    pub fn save(&self, path: &Path) -> Result<(), PresetError> {
+        let s = match path.extension().and_then(|e| e.to_str()) {
+            #[cfg(feature = "toml")]
+            Some("toml") => self.to_toml_string()?,
+            #[cfg(feature = "json")]
+            Some("json") => self.to_json_string()?,
+            _ => return Err(PresetError::UnknownFormat(path.to_owned())),
+        };
+
+        std::fs::write(path, s)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    VoiceNotLoaded(String),
+    PresetNotFound(String),
+    Style(StyleRateError),
+    Encode(SjisStringError),
+    Io(std::io::Error),
+    Format(String),
+    UnknownFormat(std::path::PathBuf),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::VoiceNotLoaded(name) => write!(f, "voice {name:?} is not loaded"),
+            Self::PresetNotFound(name) => write!(f, "preset {name:?} is not found"),
+            Self::Style(e) => write!(f, "{e}"),
+            Self::Encode(e) => write!(f, "{e}"),
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Format(e) => write!(f, "{e}"),
+            Self::UnknownFormat(path) => write!(f, "unknown preset format: {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<StyleRateError> for PresetError {
+    fn from(e: StyleRateError) -> Self {
+        Self::Style(e)
+    }
+}
+
+impl From<SjisStringError> for PresetError {
+    fn from(e: SjisStringError) -> Self {
+        Self::Encode(e)
+    }
+}
+
+impl From<std::io::Error> for PresetError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(all(test, any(feature = "toml", feature = "json")))]
+mod tests {
+    use super::*;
+
+    fn library() -> PresetLibrary {
+        let mut library = PresetLibrary::new();
+        library.engine = Some(EnginePreset {
+            volume: 1.25,
+            pause_begin: 100,
+            pause_term: 200,
+        });
+        library.insert(
+            "joy",
+            VoicePreset {
+                voice_name: "akari_44".to_owned(),
+                volume: 1.0,
+                speed: 1.5,
+                pitch: 0.75,
+                range: 1.0,
+                pause_middle: 150,
+                pause_long: 370,
+                pause_sentence: 800,
+                // TOML のテーブルはキー順になる (適用時にボイスの順に並べ直す)
+                styles: "A=0.125/J=0.5".parse().unwrap(),
+            },
+        );
+        library
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("aitalked-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn round_trips_through_files() {
+        let library = library();
+        let mut extensions = vec![];
+        #[cfg(feature = "toml")]
+        extensions.push("toml");
+        #[cfg(feature = "json")]
+        extensions.push("json");
+
+        for extension in extensions {
+            let path = temp_path(&format!("presets.{extension}"));
+            library.save(&path).unwrap();
+            let loaded = PresetLibrary::load(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(loaded.unwrap(), library, "{extension}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_extension() {
+        let path = temp_path("presets.yaml");
+
+        assert!(matches!(
+            library().save(&path),
+            Err(PresetError::UnknownFormat(p)) if p == path
+        ));
+        assert!(!path.exists());
+        assert!(matches!(
+            PresetLibrary::load(&path),
+            Err(PresetError::UnknownFormat(p)) if p == path
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+//! `ExtendFormat::JEITA_RUBY` 有効時に `text_to_kana` へ渡す、読み指定付きテキストの組み立て
+//!
+//! ```text
+//! <RUBY SPELL="かんじ">漢字</RUBY>を<BOOKMARK MARK="id"/>読む<PAUSE MSEC="500"/>
+//! ```
+//!
+//! 本文中の `<` `>` `"` `&` はタグとして解釈されないよう全角に置き換える
+
+use std::ffi::CString;
+use std::fmt;
+use std::ops::Range;
+
+use crate::binding::ExtendFormat;
+use crate::job::{to_sjis_cstring, JobError};
+
+/// この形式を解釈させるのに必要な `TtsParam::extend_format`
+pub const EXTEND_FORMAT: ExtendFormat = ExtendFormat::JEITA_RUBY;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Text(String),
+    Ruby { base: String, reading: String },
+    Bookmark(String),
+    Pause(u32),
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text(text) => write!(f, "{}", escape(text)),
+            Self::Ruby { base, reading } => write!(
+                f,
+                "<RUBY SPELL=\"{}\">{}</RUBY>",
+                escape(reading),
+                escape(base)
+            ),
+            Self::Bookmark(mark) => write!(f, "<BOOKMARK MARK=\"{}\"/>", escape(mark)),
+            Self::Pause(msec) => write!(f, "<PAUSE MSEC=\"{msec}\"/>"),
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.chars().map(escape_char).collect()
+}
+
+fn escape_char(c: char) -> char {
+    match c {
+        '<' => '＜',
+        '>' => '＞',
+        '"' => '＂',
+        '&' => '＆',
+        '\0' => ' ',
+        c => c,
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RichText {
+    segments: Vec<Segment>,
+}
+
+impl RichText {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        let text = text.into();
+
+        if let Some(Segment::Text(last)) = self.segments.last_mut() {
+            last.push_str(&text);
+        } else if !text.is_empty() {
+            self.segments.push(Segment::Text(text));
+        }
+
+        self
+    }
+
+    /// `base` を `reading` と読ませる
+    pub fn ruby(mut self, base: impl Into<String>, reading: impl Into<String>) -> Self {
+        self.segments.push(Segment::Ruby {
+            base: base.into(),
+            reading: reading.into(),
+        });
+        self
+    }
+
+    /// 合成時に `BOOKMARK` イベントとして通知される
+    pub fn bookmark(mut self, mark: impl Into<String>) -> Self {
+        self.segments.push(Segment::Bookmark(mark.into()));
+        self
+    }
+
+    pub fn pause(mut self, msec: u32) -> Self {
+        self.segments.push(Segment::Pause(msec));
+        self
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// タグを除いた表示用の本文
+    pub fn plain_text(&self) -> String {
+        self.segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Text(text) => Some(text.as_str()),
+                Segment::Ruby { base, .. } => Some(base.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// `plain_text()` の各文字が `to_string()` 上で占めるバイト範囲
+    pub(crate) fn plain_char_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges = vec![];
+        let mut offset = 0;
+
+        for segment in &self.segments {
+            let markup = segment.to_string();
+            let base = match segment {
+                Segment::Text(text) => Some((offset, text)),
+                // `<RUBY SPELL="...">base</RUBY>`
+                Segment::Ruby { base, .. } => Some((
+                    offset + markup.len() - "</RUBY>".len() - escape(base).len(),
+                    base,
+                )),
+                _ => None,
+            };
+
+            if let Some((mut start, text)) = base {
+                for c in text.chars() {
+                    let len = escape_char(c).len_utf8();
+                    ranges.push(start..start + len);
+                    start += len;
+                }
+            }
+
+            offset += markup.len();
+        }
+
+        ranges
+    }
+
+    /// `text_to_kana` にそのまま渡せる SJIS 文字列
+    pub fn to_cstring(&self) -> Result<CString, JobError> {
+        to_sjis_cstring(&self.to_string())
+    }
+}
+
+impl fmt::Display for RichText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for segment in &self.segments {
+            write!(f, "{segment}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! エンジンが返す SJIS 入力上の位置と、元の UTF-8 文字列上の位置の対応
+//!
+//! `AUTO_BOOKMARK` の名前と `get_kana` の `position` はどちらもジョブに渡した SJIS 文字列上のバイト位置
+//!
+//! `RichText` から作った場合、UTF-8 側はタグを除いた `plain_text()` になる。タグの中を指す位置は直後の文字に対応させる
+
+use std::ops::Range;
+
+use encoding_rs::SHIFT_JIS;
+
+use crate::job::{KanaOutput, SpeechEvent, TimedEvent};
+use crate::rich_text::RichText;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    text: String,
+    /// 各文字の (UTF-8 バイト位置, SJIS 上の開始位置, SJIS 上の終了位置) と末尾
+    chars: Vec<(usize, usize, usize)>,
+}
+
+/// `AUTO_BOOKMARK` から次の `AUTO_BOOKMARK` (最後は文末) までの範囲
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub tick: u64,
+    /// UTF-8 上のバイト範囲
+    pub bytes: Range<usize>,
+    /// 文字単位の範囲
+    pub chars: Range<usize>,
+}
+
+/// `get_kana` で受け取った出力の範囲と、それに当たる入力の範囲
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KanaSpan {
+    /// 出力 (`KanaOutput::kana`) 上のバイト範囲
+    pub kana: Range<usize>,
+    pub bytes: Range<usize>,
+    pub chars: Range<usize>,
+}
+
+impl SourceMap {
+    /// ジョブに渡した文字列から作る (`RichText` の場合は `from_rich_text`)
+    pub fn new(s: &str) -> Self {
+        let mut sjis = 0;
+        let mut chars = Vec::with_capacity(s.len() + 1);
+
+        for (i, c) in s.char_indices() {
+            let mut buf = [0; 4];
+            let (encoded, _encoding, errors) = SHIFT_JIS.encode(c.encode_utf8(&mut buf));
+            let len = if errors { 0 } else { encoded.len() };
+            chars.push((i, sjis, sjis + len));
+            sjis += len;
+        }
+
+        chars.push((s.len(), sjis, sjis));
+
+        Self {
+            text: s.to_owned(),
+            chars,
+        }
+    }
+
+    /// `RichText` をジョブに渡した場合。UTF-8 側は `plain_text()`
+    pub fn from_rich_text(text: &RichText) -> Self {
+        let markup = Self::new(&text.to_string());
+        let plain = text.plain_text();
+
+        let mut chars: Vec<_> = plain
+            .char_indices()
+            .zip(text.plain_char_ranges())
+            .map(|((i, _), range)| {
+                (
+                    i,
+                    markup.sjis_offset(range.start),
+                    markup.sjis_offset(range.end),
+                )
+            })
+            .collect();
+        chars.push((plain.len(), markup.sjis_len(), markup.sjis_len()));
+
+        Self { text: plain, chars }
+    }
+
+    /// UTF-8 側の文字列 (`RichText` ならタグを除いたもの)
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// ジョブに渡した SJIS 文字列の長さ
+    pub fn sjis_len(&self) -> usize {
+        self.chars[self.chars.len() - 1].1
+    }
+
+    /// UTF-8 バイト位置から文字位置
+    pub fn char_index(&self, byte: usize) -> usize {
+        self.chars.partition_point(|(b, _, _)| *b < byte)
+    }
+
+    /// UTF-8 バイト位置から SJIS バイト位置
+    pub fn sjis_offset(&self, byte: usize) -> usize {
+        self.chars[self.char_index(byte).min(self.chars.len() - 1)].1
+    }
+
+    /// SJIS バイト位置を含む文字の文字位置 (文字の途中を指す場合はその文字の先頭、タグの中を指す場合は直後の文字)
+    pub fn char_at_sjis(&self, sjis: usize) -> usize {
+        let i = self
+            .chars
+            .partition_point(|(_, s, _)| *s <= sjis)
+            .saturating_sub(1);
+
+        match sjis < self.chars[i].2 {
+            true => i,
+            false => (i + 1).min(self.chars.len() - 1),
+        }
+    }
+
+    /// SJIS バイト位置を含む文字の UTF-8 バイト位置
+    pub fn byte_at_sjis(&self, sjis: usize) -> usize {
+        self.chars[self.char_at_sjis(sjis)].0
+    }
+
+    /// SJIS 上の範囲を文字単位の範囲に広げる
+    pub fn char_range(&self, sjis: Range<usize>) -> Range<usize> {
+        let start = self.char_at_sjis(sjis.start);
+        let end = self
+            .chars
+            .partition_point(|(_, s, _)| *s < sjis.end)
+            .min(self.chars.len() - 1);
+
+        start..end.max(start)
+    }
+
+    /// SJIS 上の範囲を UTF-8 上のバイト範囲に広げる
+    pub fn byte_range(&self, sjis: Range<usize>) -> Range<usize> {
+        let chars = self.char_range(sjis);
+        self.chars[chars.start].0..self.chars[chars.end].0
+    }
+
+    /// 変換ジョブの出力を、入力のどこまでを変換した結果かで区切る
+    pub fn kana_spans(&self, output: &KanaOutput) -> Vec<KanaSpan> {
+        let mut kana = 0;
+        let mut source = 0;
+        let mut spans = vec![];
+
+        for position in &output.positions {
+            if position.kana <= kana {
+                continue;
+            }
+
+            let end = (position.source as usize).max(source);
+            let chars = self.char_range(source..end);
+            spans.push(KanaSpan {
+                kana: kana..position.kana,
+                bytes: self.chars[chars.start].0..self.chars[chars.end].0,
+                chars,
+            });
+
+            kana = position.kana;
+            source = end;
+        }
+
+        spans
+    }
+
+    /// 音声合成ジョブのイベントから、読み上げ中の範囲を時刻順に並べる
+    pub fn auto_bookmark_spans(&self, events: &[TimedEvent]) -> Vec<SourceSpan> {
+        let mut bookmarks: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e.event {
+                SpeechEvent::AutoBookmark(position) => Some((e.tick, position as usize)),
+                _ => None,
+            })
+            .collect();
+        bookmarks.sort_by_key(|(tick, _)| *tick);
+
+        let ends = bookmarks
+            .iter()
+            .skip(1)
+            .map(|(_, position)| *position)
+            .chain([self.sjis_len()]);
+
+        bookmarks
+            .iter()
+            .zip(ends)
+            .map(|(&(tick, start), end)| {
+                let chars = self.char_range(start..end.max(start));
+                SourceSpan {
+                    tick,
+                    bytes: self.chars[chars.start].0..self.chars[chars.end].0,
+                    chars,
+                }
+            })
+            .collect()
+    }
+}
+
+impl From<&str> for SourceMap {
+    fn from(s: &str) -> Self {
+        Self::new(s)
+    }
+}
+
+impl From<&String> for SourceMap {
+    fn from(s: &String) -> Self {
+        Self::new(s)
+    }
+}
+
+impl From<&RichText> for SourceMap {
+    fn from(text: &RichText) -> Self {
+        Self::from_rich_text(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_rich_text_to_plain_text() {
+        let text = RichText::new()
+            .ruby("漢字", "かんじ")
+            .text("を")
+            .bookmark("id")
+            .text("読む<");
+        let map = SourceMap::from_rich_text(&text);
+
+        // <RUBY SPELL="かんじ">漢字</RUBY>を<BOOKMARK MARK="id"/>読む＜
+        assert_eq!(map.text(), "漢字を読む<");
+        assert_eq!(map.sjis_len(), 61);
+
+        assert_eq!(map.byte_at_sjis(21), 0);
+        assert_eq!(map.byte_at_sjis(32), 6);
+        assert_eq!(map.byte_at_sjis(55), 9);
+        assert_eq!(map.byte_at_sjis(59), 15);
+        assert_eq!(map.sjis_offset(12), 57);
+
+        // タグの中は直後の文字
+        assert_eq!(map.byte_at_sjis(0), 0);
+        assert_eq!(map.byte_at_sjis(40), 9);
+
+        assert_eq!(map.byte_range(21..34), 0..9);
+        assert_eq!(map.byte_range(34..map.sjis_len()), 9..16);
+    }
+
+    #[test]
+    fn plain_text_maps_every_char() {
+        let map = SourceMap::from("アa、b");
+
+        assert_eq!(map.text(), "アa、b");
+        assert_eq!(map.sjis_len(), 6);
+        assert_eq!(map.byte_at_sjis(1), 0);
+        assert_eq!(map.byte_at_sjis(2), 3);
+        assert_eq!(map.byte_range(3..6), 4..8);
+    }
+}
@@ -0,0 +1,293 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::binding::*;
+
+/// 喜び
+pub const JOY: &str = "J";
+/// 怒り
+pub const ANGER: &str = "A";
+/// 悲しみ
+pub const SADNESS: &str = "S";
+
+const DEFAULT_SEPARATOR: char = '/';
+const DEFAULT_ASSIGN: char = '=';
+
+/// `SpeakerParam::style_rate` の型付き表現
+///
+/// 並び順と区切り文字はエンジンが返した文字列のものを保持し、そのまま書き戻せるようにしている
+#[derive(Debug, Clone, PartialEq)]
+pub struct StyleRates {
+    entries: Vec<(String, f32)>,
+    separator: char,
+    assign: char,
+}
+
+impl Default for StyleRates {
+    fn default() -> Self {
+        Self {
+            entries: vec![],
+            separator: DEFAULT_SEPARATOR,
+            assign: DEFAULT_ASSIGN,
+        }
+    }
+}
+
+impl StyleRates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, name: &str) -> Option<f32> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, rate)| *rate)
+    }
+
+    /// 既存のスタイルは値を上書きし、無ければ末尾に追加する
+    pub fn set(&mut self, name: impl Into<String>, rate: f32) -> Result<(), StyleRateError> {
+        let name = name.into();
+        validate_name(&name)?;
+        validate_rate(&name, rate)?;
+
+        match self.entries.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = rate,
+            None => self.entries.push((name, rate)),
+        }
+
+        Ok(())
+    }
+
+    pub fn with(mut self, name: impl Into<String>, rate: f32) -> Result<Self, StyleRateError> {
+        self.set(name, rate)?;
+        Ok(self)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<f32> {
+        let index = self.entries.iter().position(|(n, _)| n == name)?;
+        Some(self.entries.remove(index).1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f32)> {
+        self.entries.iter().map(|(n, rate)| (n.as_str(), *rate))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(n, _)| n.as_str())
+    }
+
+    /// `supported` (ボイスの既定値として `get_param` が返したもの) に無いスタイルが含まれていればエラー
+    pub fn validate(&self, supported: &StyleRates) -> Result<(), StyleRateError> {
+        for (name, rate) in self.iter() {
+            if supported.get(name).is_none() {
+                return Err(StyleRateError::Unsupported(name.to_owned()));
+            }
+            validate_rate(name, rate)?;
+        }
+
+        Ok(())
+    }
+
+    /// `supported` の並び順と区切り文字に合わせ、指定の無いスタイルを0.0で埋めたものを返す
+    pub fn normalized(&self, supported: &StyleRates) -> Result<StyleRates, StyleRateError> {
+        self.validate(supported)?;
+
+        Ok(StyleRates {
+            entries: supported
+                .names()
+                .map(|name| (name.to_owned(), self.get(name).unwrap_or(0.0)))
+                .collect(),
+            separator: supported.separator,
+            assign: supported.assign,
+        })
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), StyleRateError> {
+    if name.is_empty()
+        || name
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '/' | ';' | ',' | '=' | ':'))
+    {
+        return Err(StyleRateError::InvalidName(name.to_owned()));
+    }
+
+    Ok(())
+}
+
+fn validate_rate(name: &str, rate: f32) -> Result<(), StyleRateError> {
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(StyleRateError::OutOfRange {
+            name: name.to_owned(),
+            rate,
+        });
+    }
+
+    Ok(())
+}
+
+impl FromStr for StyleRates {
+    type Err = StyleRateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mut rates = StyleRates::default();
+
+        if s.is_empty() {
+            return Ok(rates);
+        }
+
+        if let Some(separator) = s.chars().find(|c| matches!(c, '/' | ';' | ',')) {
+            rates.separator = separator;
+        }
+
+        if let Some(assign) = s.chars().find(|c| matches!(c, '=' | ':')) {
+            rates.assign = assign;
+        }
+
+        for entry in s.split(rates.separator).map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+
+            let (name, rate) = entry
+                .split_once(rates.assign)
+                .ok_or_else(|| StyleRateError::Malformed(entry.to_owned()))?;
+
+            let name = name.trim();
+            let rate = rate
+                .trim()
+                .parse()
+                .map_err(|_| StyleRateError::Malformed(entry.to_owned()))?;
+
+            rates.set(name, rate)?;
+        }
+
+        Ok(rates)
+    }
+}
+
+impl fmt::Display for StyleRates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, rate)) in self.entries.iter().enumerate() {
+            if i != 0 {
+                write!(f, "{}", self.separator)?;
+            }
+            write!(f, "{name}{}{rate}", self.assign)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StyleRateError {
+    Malformed(String),
+    InvalidName(String),
+    OutOfRange { name: String, rate: f32 },
+    Unsupported(String),
+    Encode(SjisStringError),
+}
+
+impl fmt::Display for StyleRateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(entry) => write!(f, "malformed style rate entry: {entry:?}"),
+            Self::InvalidName(name) => write!(f, "invalid style name: {name:?}"),
+            Self::OutOfRange { name, rate } => {
+                write!(
+                    f,
+                    "style rate of {name:?} is out of range (0.0..=1.0): {rate}"
+                )
+            }
+            Self::Unsupported(name) => write!(f, "style {name:?} is not supported by the voice"),
+            Self::Encode(e) => write!(f, "failed to encode style rate: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StyleRateError {}
+
+impl From<SjisStringError> for StyleRateError {
+    fn from(e: SjisStringError) -> Self {
+        Self::Encode(e)
+    }
+}
+
+impl SpeakerParam {
+    pub fn style_rates(&self) -> Result<StyleRates, StyleRateError> {
+        decode_sjis_cchar_slice(&self.style_rate).parse()
+    }
+
+    /// 現在の `style_rate` をボイスの対応スタイルとみなして検証した上で書き込む
+    pub fn set_style_rates(&mut self, rates: &StyleRates) -> Result<(), StyleRateError> {
+        let supported = self.style_rates()?;
+        let rates = rates.normalized(&supported)?;
+        encode_sjis_cchar_slice(&rates.to_string(), &mut self.style_rate)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for StyleRates {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (name, rate) in self.iter() {
+            map.serialize_entry(name, &rate)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for StyleRates {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = StyleRates;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of style names to rates")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut rates = StyleRates::default();
+                while let Some((name, rate)) = map.next_entry::<String, f32>()? {
+                    rates.set(name, rate).map_err(serde::de::Error::custom)?;
+                }
+                Ok(rates)
+            }
+        }
+
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_both_separator_styles() {
+        for s in ["J=0.5/A=0.125/S=0.333", "J:0.5;A:0.125;S:0.333"] {
+            let rates: StyleRates = s.parse().unwrap();
+            assert_eq!(rates.to_string(), s);
+            assert_eq!(rates.to_string().parse::<StyleRates>().unwrap(), rates);
+        }
+    }
+}
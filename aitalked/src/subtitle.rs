@@ -0,0 +1,488 @@
+//! 合成した音声に合わせた字幕 (SRT・WebVTT・ASS) の書き出し
+//!
+//! 文や節ごとの表示時間は `AUTO_BOOKMARK` の位置から決める (`AUTO_BOOKMARK` を含まない文は前後の位置から補間する)。
+//! `ExtendFormat::AUTO_BOOKMARK` を有効にせずに合成した場合は、音声全体の長さを文字数で按分する
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::job::SpeechEvent;
+use crate::source_map::SourceMap;
+use crate::timeline::{Timeline, Track};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Granularity {
+    /// 。！？ と改行で区切る
+    #[default]
+    Sentence,
+    /// 文に加えて 、， でも区切る
+    Clause,
+}
+
+impl Granularity {
+    fn is_break(&self, c: char) -> bool {
+        match c {
+            '。' | '．' | '！' | '？' | '!' | '?' | '…' => true,
+            '、' | '，' | ',' => *self == Self::Clause,
+            _ => false,
+        }
+    }
+}
+
+/// 区切りの直後に続けて同じ字幕に含める閉じ括弧など
+fn is_trailing(c: char) -> bool {
+    matches!(c, '」' | '』' | '）' | ')' | '】' | '〉' | '》' | '”' | '’')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    /// tick
+    pub start: u64,
+    /// tick
+    pub end: u64,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    /// tick
+    pub start: u64,
+    /// tick
+    pub end: u64,
+    pub text: String,
+    /// `SourceMap::text` 上のバイト範囲
+    pub source: Range<usize>,
+    /// `AUTO_BOOKMARK` ごとの区切り (カラオケ表示用)
+    pub words: Vec<Word>,
+}
+
+/// `text` を区切った範囲 (前後の空白を除く)
+fn split(text: &str, granularity: Granularity) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let end = if c == '\n' {
+            Some((i, i + 1))
+        } else if granularity.is_break(c) {
+            let mut end = i + c.len_utf8();
+            while let Some(&(j, next)) = chars.peek() {
+                if !(granularity.is_break(next) || is_trailing(next)) {
+                    break;
+                }
+                end = j + next.len_utf8();
+                chars.next();
+            }
+            Some((end, end))
+        } else {
+            None
+        };
+
+        if let Some((end, next)) = end {
+            ranges.push(start..end);
+            start = next;
+        }
+    }
+
+    ranges.push(start..text.len());
+
+    ranges
+        .into_iter()
+        .filter_map(|range| {
+            let slice = &text[range.clone()];
+            let trimmed = slice.trim();
+            if trimmed.is_empty() {
+                return None;
+            }
+            let offset = range.start + (slice.len() - slice.trim_start().len());
+            Some(offset..offset + trimmed.len())
+        })
+        .collect()
+}
+
+/// `source` はジョブに渡した文字列か `RichText` (字幕にはタグを除いた本文を使う)
+pub fn cues(
+    source: impl Into<SourceMap>,
+    timeline: &Timeline,
+    granularity: Granularity,
+) -> Vec<Cue> {
+    let map = source.into();
+    let text = map.text();
+    let ranges = split(text, granularity);
+
+    let mut bookmarks: Vec<_> = timeline
+        .track(Track::AutoBookmark)
+        .filter_map(|s| match s.event {
+            SpeechEvent::AutoBookmark(position) => {
+                Some((s.start, s.end, map.byte_at_sjis(position as usize)))
+            }
+            _ => None,
+        })
+        .collect();
+
+    if bookmarks.is_empty() {
+        return proportional_cues(text, ranges, timeline.end());
+    }
+
+    bookmarks.sort_by_key(|&(start, _, byte)| (byte, start));
+
+    // 文字位置と時刻の対応点 (先頭・末尾と各 `AUTO_BOOKMARK`)
+    let anchors: Vec<_> = [(0, 0)]
+        .into_iter()
+        .chain(
+            bookmarks
+                .iter()
+                .map(|&(start, _, byte)| (map.char_index(byte), start)),
+        )
+        .chain([(map.char_index(text.len()), timeline.end())])
+        .collect();
+    let tick_at = |byte: usize| interpolate(&anchors, map.char_index(byte));
+
+    let mut cues: Vec<Cue> = vec![];
+
+    for range in ranges {
+        let inside: Vec<_> = bookmarks
+            .iter()
+            .filter(|(_, _, byte)| range.contains(byte))
+            .collect();
+
+        let cue = match (inside.first(), inside.last()) {
+            (Some(&&(start, _, _)), Some(&&(_, end, _))) => {
+                let mut words = vec![];
+                for (n, &&(start, end, byte)) in inside.iter().enumerate() {
+                    let from = if n == 0 { range.start } else { byte };
+                    let to = inside
+                        .get(n + 1)
+                        .map(|(_, _, next)| *next)
+                        .unwrap_or(range.end)
+                        .max(from);
+
+                    words.push(Word {
+                        start,
+                        end,
+                        text: text[from..to].to_owned(),
+                    });
+                }
+
+                Cue {
+                    start,
+                    end,
+                    text: text[range.clone()].to_owned(),
+                    source: range,
+                    words,
+                }
+            }
+            // `AUTO_BOOKMARK` の無い文は前後の位置から補間する
+            _ => {
+                let start = tick_at(range.start);
+                let end = tick_at(range.end).max(start);
+
+                Cue {
+                    start,
+                    end,
+                    text: text[range.clone()].to_owned(),
+                    words: vec![Word {
+                        start,
+                        end,
+                        text: text[range.clone()].to_owned(),
+                    }],
+                    source: range,
+                }
+            }
+        };
+
+        // 前の字幕が補間した字幕に重ならないようにする
+        if let Some(last) = cues.last_mut() {
+            if last.end > cue.start {
+                last.end = cue.start.max(last.start);
+                for word in &mut last.words {
+                    word.end = word.end.min(last.end);
+                    word.start = word.start.min(word.end);
+                }
+            }
+        }
+
+        cues.push(cue);
+    }
+
+    cues
+}
+
+/// `anchors` (文字位置, tick) を線形に補間した `chars` の時刻
+fn interpolate(anchors: &[(usize, u64)], chars: usize) -> u64 {
+    // 先頭は (0, 0) なので `i` は 1 以上
+    let i = anchors.partition_point(|&(c, _)| c <= chars);
+    let (c0, t0) = anchors[i - 1];
+    let Some(&(c1, t1)) = anchors.get(i) else {
+        return t0;
+    };
+
+    match c1 > c0 && t1 > t0 {
+        true => t0 + (t1 - t0) * (chars - c0) as u64 / (c1 - c0) as u64,
+        false => t0,
+    }
+}
+
+fn proportional_cues(text: &str, ranges: Vec<Range<usize>>, end: u64) -> Vec<Cue> {
+    let total: usize = ranges.iter().map(|r| text[r.clone()].chars().count()).sum();
+    let mut chars = 0;
+
+    ranges
+        .into_iter()
+        .map(|range| {
+            let start = end * chars as u64 / total.max(1) as u64;
+            chars += text[range.clone()].chars().count();
+            let end = end * chars as u64 / total.max(1) as u64;
+
+            Cue {
+                start,
+                end,
+                text: text[range.clone()].to_owned(),
+                words: vec![Word {
+                    start,
+                    end,
+                    text: text[range.clone()].to_owned(),
+                }],
+                source: range,
+            }
+        })
+        .collect()
+}
+
+/// `HH:MM:SS{separator}mmm`
+fn timestamp(ms: u64, separator: char) -> String {
+    format!(
+        "{:02}:{:02}:{:02}{separator}{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// `H:MM:SS.cc`
+fn ass_timestamp(cs: u64) -> String {
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        cs / 360_000,
+        cs / 6000 % 60,
+        cs / 100 % 60,
+        cs % 100
+    )
+}
+
+fn ms(timeline: &Timeline, tick: u64) -> u64 {
+    timeline.tick_to_ms(tick).round() as u64
+}
+
+pub fn write_srt<W: Write>(mut writer: W, cues: &[Cue], timeline: &Timeline) -> io::Result<()> {
+    for (i, cue) in cues.iter().enumerate() {
+        writeln!(writer, "{}", i + 1)?;
+        writeln!(
+            writer,
+            "{} --> {}",
+            timestamp(ms(timeline, cue.start), ','),
+            timestamp(ms(timeline, cue.end), ',')
+        )?;
+        writeln!(writer, "{}", cue.text.trim())?;
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+pub fn write_vtt<W: Write>(mut writer: W, cues: &[Cue], timeline: &Timeline) -> io::Result<()> {
+    writeln!(writer, "WEBVTT")?;
+    writeln!(writer)?;
+
+    for cue in cues {
+        let text = cue
+            .text
+            .trim()
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+
+        writeln!(
+            writer,
+            "{} --> {}",
+            timestamp(ms(timeline, cue.start), '.'),
+            timestamp(ms(timeline, cue.end), '.')
+        )?;
+        writeln!(writer, "{text}")?;
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssOptions {
+    /// 語ごとに `\k` で色が変わるようにする
+    pub karaoke: bool,
+    pub font_name: String,
+    pub font_size: u32,
+    pub play_res: (u32, u32),
+}
+
+impl Default for AssOptions {
+    fn default() -> Self {
+        Self {
+            karaoke: false,
+            font_name: "Meiryo".to_owned(),
+            font_size: 64,
+            play_res: (1920, 1080),
+        }
+    }
+}
+
+/// 上書き指定として解釈される文字を全角にする
+fn ass_escape(s: &str) -> String {
+    s.trim_end_matches(['\r', '\n'])
+        .replace('{', "｛")
+        .replace('}', "｝")
+        .replace('\\', "＼")
+        .replace('\n', "\\N")
+}
+
+pub fn write_ass<W: Write>(
+    mut writer: W,
+    cues: &[Cue],
+    timeline: &Timeline,
+    options: &AssOptions,
+) -> io::Result<()> {
+    let cs = |tick| (timeline.tick_to_ms(tick) / 10.0).round() as u64;
+
+    writeln!(writer, "[Script Info]")?;
+    writeln!(writer, "ScriptType: v4.00+")?;
+    writeln!(writer, "PlayResX: {}", options.play_res.0)?;
+    writeln!(writer, "PlayResY: {}", options.play_res.1)?;
+    writeln!(writer, "WrapStyle: 0")?;
+    writeln!(writer)?;
+    writeln!(writer, "[V4+ Styles]")?;
+    writeln!(
+        writer,
+        "Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding"
+    )?;
+    writeln!(
+        writer,
+        "Style: Default,{},{},&H00FFFFFF,&H0000FFFF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,3,0,2,40,40,40,128",
+        options.font_name, options.font_size
+    )?;
+    writeln!(writer)?;
+    writeln!(writer, "[Events]")?;
+    writeln!(
+        writer,
+        "Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text"
+    )?;
+
+    for cue in cues {
+        let start = cs(cue.start);
+        let end = cs(cue.end);
+
+        let mut text = String::new();
+        if options.karaoke {
+            // 丸めの誤差が積み重ならないよう、各語の終わりを丸めてから差を取る
+            let mut cursor = start;
+            for word in &cue.words {
+                let word_end = cs(word.end).clamp(cursor, end);
+                let _ = write!(
+                    text,
+                    "{{\\k{}}}{}",
+                    word_end - cursor,
+                    ass_escape(&word.text)
+                );
+                cursor = word_end;
+            }
+        } else {
+            text = ass_escape(cue.text.trim());
+        }
+
+        writeln!(
+            writer,
+            "Dialogue: 0,{},{},Default,,0,0,0,,{text}",
+            ass_timestamp(start),
+            ass_timestamp(end)
+        )?;
+    }
+
+    Ok(())
+}
+
+fn to_string(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+    let mut buffer = vec![];
+    write(&mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+pub fn srt_to_string(cues: &[Cue], timeline: &Timeline) -> String {
+    to_string(|w| write_srt(w, cues, timeline))
+}
+
+pub fn vtt_to_string(cues: &[Cue], timeline: &Timeline) -> String {
+    to_string(|w| write_vtt(w, cues, timeline))
+}
+
+pub fn ass_to_string(cues: &[Cue], timeline: &Timeline, options: &AssOptions) -> String {
+    to_string(|w| write_ass(w, cues, timeline, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::TimedEvent;
+    use crate::timeline::TICKS_PER_SECOND;
+
+    fn timeline(bookmarks: &[(u64, u32)], end: usize) -> Timeline {
+        let events: Vec<_> = bookmarks
+            .iter()
+            .map(|&(tick, position)| TimedEvent {
+                tick,
+                event: SpeechEvent::AutoBookmark(position),
+            })
+            .collect();
+        Timeline::from_events(TICKS_PER_SECOND as u32, &events, end)
+    }
+
+    fn times(cues: &[Cue]) -> Vec<(u64, u64, &str)> {
+        cues.iter()
+            .map(|c| (c.start, c.end, c.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn interpolates_sentence_without_bookmark() {
+        let timeline = timeline(&[(0, 0), (600, 14)], 900);
+        let cues = cues("あいう。かき。さし。", &timeline, Granularity::Sentence);
+
+        assert_eq!(
+            times(&cues),
+            [
+                (0, 342, "あいう。"),
+                (342, 600, "かき。"),
+                (600, 900, "さし。")
+            ]
+        );
+        assert_eq!(cues[0].words[0].end, 342);
+    }
+
+    #[test]
+    fn falls_back_to_proportional_without_bookmarks() {
+        let timeline = timeline(&[], 1000);
+        let cues = cues("あいう。か。", &timeline, Granularity::Sentence);
+
+        assert_eq!(times(&cues), [(0, 666, "あいう。"), (666, 1000, "か。")]);
+    }
+
+    #[test]
+    fn accepts_bookmarks_out_of_text_order() {
+        let timeline = timeline(&[(0, 4), (300, 0)], 600);
+        let cues = cues("あいう。", &timeline, Granularity::Sentence);
+
+        assert_eq!(cues.len(), 1);
+        let words: String = cues[0].words.iter().map(|w| w.text.as_str()).collect();
+        assert_eq!(words, "あいう。");
+    }
+}
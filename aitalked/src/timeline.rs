@@ -0,0 +1,241 @@
+//! コールバックに渡される tick と音声上の位置の対応
+//!
+//! tick は音声の先頭からのミリ秒。イベントは種類ごとに、次の同じ種類のイベント (最後は音声の終わり) までを長さとする
+
+use std::ops::Range;
+
+use crate::job::{SpeechChunk, SpeechEvent, SpeechOutput, TimedEvent};
+
+/// SDK のヘッダーには tick の単位が書かれていないため、`(Pau MSEC=...)` などエンジンの他の時間指定に合わせてミリ秒とみなしている
+pub const TICKS_PER_SECOND: u64 = 1000;
+
+/// `hz` で `sample` 番目のサンプルの tick (`Timeline` を持たない場合に)
+pub fn sample_to_tick(hz: u32, sample: usize) -> u64 {
+    match hz {
+        0 => 0,
+        hz => sample as u64 * TICKS_PER_SECOND / hz as u64,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Track {
+    Phoneme,
+    Bookmark,
+    AutoBookmark,
+}
+
+impl Track {
+    const ALL: [Self; 3] = [Self::Phoneme, Self::Bookmark, Self::AutoBookmark];
+
+    pub fn of(event: &SpeechEvent) -> Self {
+        match event {
+            SpeechEvent::Phoneme(_) => Self::Phoneme,
+            SpeechEvent::Bookmark(_) => Self::Bookmark,
+            SpeechEvent::AutoBookmark(_) => Self::AutoBookmark,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub event: SpeechEvent,
+    /// tick
+    pub start: u64,
+    /// tick
+    pub end: u64,
+}
+
+impl Segment {
+    pub fn track(&self) -> Track {
+        Track::of(&self.event)
+    }
+
+    pub fn ticks(&self) -> Range<u64> {
+        self.start..self.end
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeline {
+    hz: u32,
+    samples: usize,
+    /// 開始 tick 順
+    segments: Vec<Segment>,
+    /// 種類ごとの、まだ次のイベントが来ていない区間
+    open: [Option<usize>; 3],
+}
+
+impl Timeline {
+    /// `hz` は `AitalkedConfig::hz_voice_db` (`Aitalked::hz_voice_db`)
+    pub fn new(hz: u32) -> Self {
+        Self {
+            hz,
+            samples: 0,
+            segments: vec![],
+            open: [None; 3],
+        }
+    }
+
+    pub fn from_output(hz: u32, output: &SpeechOutput) -> Self {
+        Self::from_events(hz, &output.events, output.samples.len())
+    }
+
+    pub fn from_events(hz: u32, events: &[TimedEvent], samples: usize) -> Self {
+        let mut events = events.to_vec();
+        events.sort_by_key(|e| e.tick);
+
+        let mut timeline = Self::new(hz);
+        for event in events {
+            timeline.push_event(event);
+        }
+        timeline.push_samples(samples);
+        timeline
+    }
+
+    /// `SpeechStream` から受け取った順に追加する
+    pub fn push(&mut self, chunk: &SpeechChunk) {
+        match chunk {
+            SpeechChunk::Audio { samples, .. } => self.push_samples(samples.len()),
+            SpeechChunk::Event(event) => self.push_event(event.clone()),
+        }
+    }
+
+    pub fn push_samples(&mut self, samples: usize) {
+        self.samples += samples;
+
+        let end = self.end();
+        for index in self.open.into_iter().flatten() {
+            let segment = &mut self.segments[index];
+            segment.end = segment.end.max(end);
+        }
+    }
+
+    pub fn push_event(&mut self, event: TimedEvent) {
+        let track = Track::of(&event.event).index();
+
+        // 順序が逆転した tick でも区間の長さは負にしない
+        if let Some(index) = self.open[track] {
+            let segment = &mut self.segments[index];
+            segment.end = event.tick.max(segment.start);
+        }
+
+        let segment = Segment {
+            start: event.tick,
+            end: event.tick.max(self.end()),
+            event: event.event,
+        };
+        let index = self.segments.partition_point(|s| s.start <= segment.start);
+        self.segments.insert(index, segment);
+
+        for open in self.open.iter_mut().flatten() {
+            if *open >= index {
+                *open += 1;
+            }
+        }
+        self.open[track] = Some(index);
+    }
+
+    pub fn hz(&self) -> u32 {
+        self.hz
+    }
+
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// 音声の終わりの tick
+    pub fn end(&self) -> u64 {
+        self.sample_to_tick(self.samples)
+    }
+
+    pub fn tick_to_sample(&self, tick: u64) -> usize {
+        (tick * self.hz as u64 / TICKS_PER_SECOND) as usize
+    }
+
+    pub fn sample_to_tick(&self, sample: usize) -> u64 {
+        sample_to_tick(self.hz, sample)
+    }
+
+    pub fn tick_to_ms(&self, tick: u64) -> f64 {
+        tick as f64 * 1000.0 / TICKS_PER_SECOND as f64
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn track(&self, track: Track) -> impl Iterator<Item = &Segment> {
+        self.segments.iter().filter(move |s| s.track() == track)
+    }
+
+    /// `tick` の時点で有効な区間 (種類ごとに高々1つ)
+    pub fn at(&self, tick: u64) -> Vec<&Segment> {
+        Track::ALL
+            .into_iter()
+            .filter_map(|track| {
+                self.track(track)
+                    .take_while(|s| s.start <= tick)
+                    .filter(|s| tick < s.end)
+                    .last()
+            })
+            .collect()
+    }
+
+    /// 区間に当たるサンプルの範囲
+    pub fn sample_range(&self, segment: &Segment) -> Range<usize> {
+        let end = self.tick_to_sample(segment.end).min(self.samples);
+        self.tick_to_sample(segment.start).min(end)..end
+    }
+
+    pub fn ms_range(&self, segment: &Segment) -> Range<f64> {
+        self.tick_to_ms(segment.start)..self.tick_to_ms(segment.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(tick: u64, name: &str) -> TimedEvent {
+        TimedEvent {
+            tick,
+            event: SpeechEvent::Phoneme(name.to_owned()),
+        }
+    }
+
+    #[test]
+    fn converts_ticks_and_samples() {
+        let timeline = Timeline::new(22050);
+        assert_eq!(timeline.tick_to_sample(1), 22);
+        assert_eq!(timeline.tick_to_sample(1000), 22050);
+        assert_eq!(timeline.tick_to_sample(1500), 33075);
+        assert_eq!(timeline.sample_to_tick(22049), 999);
+        assert_eq!(timeline.sample_to_tick(22050), 1000);
+        assert_eq!(timeline.sample_to_tick(33075), 1500);
+
+        let timeline = Timeline::new(44100);
+        assert_eq!(timeline.tick_to_sample(1), 44);
+        assert_eq!(timeline.tick_to_sample(1000), 44100);
+        assert_eq!(timeline.tick_to_sample(250), 11025);
+        assert_eq!(timeline.sample_to_tick(441), 10);
+        assert_eq!(timeline.sample_to_tick(44100), 1000);
+        assert_eq!(timeline.sample_to_tick(11025), 250);
+    }
+
+    #[test]
+    fn out_of_order_tick_does_not_reverse_segment() {
+        let mut timeline = Timeline::new(22050);
+        timeline.push_event(event(100, "a"));
+        timeline.push_event(event(50, "i"));
+        timeline.push_samples(22050);
+
+        for segment in timeline.segments() {
+            assert!(segment.start <= segment.end, "{segment:?}");
+        }
+        assert_eq!(timeline.segments()[1].ticks(), 100..100);
+    }
+}
@@ -0,0 +1,429 @@
+//! `PH_LABEL` の音素から口の形 (ビセーム) のキーフレームを作る
+//!
+//! 音素をまず `Viseme` に分類し、それを `VisemeSet` で出力先の名前に置き換える。
+//! 名前が割り当てられていない音素 (子音の多く) は直後の音素と同じ口の形にする
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+
+use crate::job::{SpeechChunk, SpeechEvent};
+use crate::timeline::{self, Timeline, Track};
+
+/// Oculus Lipsync の15種類に合わせた分類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Viseme {
+    Sil,
+    PP,
+    FF,
+    TH,
+    DD,
+    KK,
+    CH,
+    SS,
+    NN,
+    RR,
+    A,
+    E,
+    I,
+    O,
+    U,
+}
+
+impl Viseme {
+    /// 半母音や h などの決まった口の形を持たない音素は `None`
+    pub fn classify(phoneme: &str) -> Option<Self> {
+        let phoneme = phoneme.trim();
+
+        if phoneme == "N" {
+            return Some(Self::NN);
+        }
+
+        let viseme = match phoneme.to_ascii_lowercase().as_str() {
+            "a" => Self::A,
+            "i" => Self::I,
+            "u" => Self::U,
+            "e" => Self::E,
+            "o" => Self::O,
+            "pau" | "sil" | "cl" | "q" => Self::Sil,
+            "p" | "py" | "b" | "by" | "m" | "my" => Self::PP,
+            "f" | "v" => Self::FF,
+            "t" | "ty" | "d" | "dy" => Self::DD,
+            "k" | "ky" | "kw" | "g" | "gy" | "gw" => Self::KK,
+            "ch" | "j" | "jy" | "sh" | "zy" => Self::CH,
+            "s" | "z" | "ts" => Self::SS,
+            "n" | "ny" => Self::NN,
+            "r" | "ry" => Self::RR,
+            _ => return None,
+        };
+
+        Some(viseme)
+    }
+}
+
+/// 分類から出力する名前への対応
+///
+/// 空文字列は口を閉じた状態 (全ての口の形の重みを 0 にする) を表す
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct VisemeSet {
+    names: BTreeMap<Viseme, String>,
+}
+
+impl VisemeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, viseme: Viseme, name: impl Into<String>) -> Self {
+        self.names.insert(viseme, name.into());
+        self
+    }
+
+    pub fn get(&self, viseme: Viseme) -> Option<&str> {
+        self.names.get(&viseme).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, viseme: Viseme, name: impl Into<String>) {
+        self.names.insert(viseme, name.into());
+    }
+
+    pub fn remove(&mut self, viseme: Viseme) -> Option<String> {
+        self.names.remove(&viseme)
+    }
+
+    fn name(&self, phoneme: &str) -> Option<String> {
+        Viseme::classify(phoneme)
+            .and_then(|v| self.get(v))
+            .map(str::to_owned)
+    }
+
+    fn silence(&self) -> String {
+        self.get(Viseme::Sil).unwrap_or_default().to_owned()
+    }
+
+    /// あいうえお と ん (口を閉じる)
+    pub fn japanese() -> Self {
+        Self::new()
+            .with(Viseme::A, "あ")
+            .with(Viseme::I, "い")
+            .with(Viseme::U, "う")
+            .with(Viseme::E, "え")
+            .with(Viseme::O, "お")
+            .with(Viseme::FF, "う")
+            .with(Viseme::PP, "ん")
+            .with(Viseme::NN, "ん")
+            .with(Viseme::Sil, "ん")
+    }
+
+    /// VRM 1.0 の表情プリセット名
+    pub fn vrm() -> Self {
+        Self::new()
+            .with(Viseme::A, "aa")
+            .with(Viseme::I, "ih")
+            .with(Viseme::U, "ou")
+            .with(Viseme::E, "ee")
+            .with(Viseme::O, "oh")
+            .with(Viseme::FF, "ou")
+            .with(Viseme::PP, "")
+            .with(Viseme::NN, "")
+            .with(Viseme::Sil, "")
+    }
+
+    /// Live2D Cubism の標準パラメータ ID
+    pub fn live2d() -> Self {
+        Self::new()
+            .with(Viseme::A, "ParamA")
+            .with(Viseme::I, "ParamI")
+            .with(Viseme::U, "ParamU")
+            .with(Viseme::E, "ParamE")
+            .with(Viseme::O, "ParamO")
+            .with(Viseme::FF, "ParamU")
+            .with(Viseme::PP, "")
+            .with(Viseme::NN, "")
+            .with(Viseme::Sil, "")
+    }
+
+    /// Oculus Lipsync のビセーム名
+    pub fn oculus() -> Self {
+        Self::new()
+            .with(Viseme::Sil, "sil")
+            .with(Viseme::PP, "PP")
+            .with(Viseme::FF, "FF")
+            .with(Viseme::TH, "TH")
+            .with(Viseme::DD, "DD")
+            .with(Viseme::KK, "kk")
+            .with(Viseme::CH, "CH")
+            .with(Viseme::SS, "SS")
+            .with(Viseme::NN, "nn")
+            .with(Viseme::RR, "RR")
+            .with(Viseme::A, "aa")
+            .with(Viseme::E, "E")
+            .with(Viseme::I, "ih")
+            .with(Viseme::O, "oh")
+            .with(Viseme::U, "ou")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Keyframe {
+    /// tick (ミリ秒)
+    pub start: u64,
+    /// tick (ミリ秒)
+    pub end: u64,
+    pub viseme: String,
+}
+
+/// 音素を受け取った順にキーフレームにする
+///
+/// キーフレームは次の音素 (子音なら口の形が決まる音素) を受け取った時点で確定する
+#[derive(Debug, Clone)]
+pub struct VisemeTracker {
+    set: VisemeSet,
+    current: Option<(u64, Option<String>)>,
+    waiting: Vec<(u64, u64)>,
+}
+
+impl VisemeTracker {
+    pub fn new(set: VisemeSet) -> Self {
+        Self {
+            set,
+            current: None,
+            waiting: vec![],
+        }
+    }
+
+    pub fn push_phoneme(&mut self, tick: u64, phoneme: &str) -> Vec<Keyframe> {
+        let mut keyframes = self.close(tick);
+
+        let name = self.set.name(phoneme);
+        if let Some(name) = &name {
+            keyframes.extend(self.resolve(name));
+        }
+
+        self.current = Some((tick, name));
+        keyframes
+    }
+
+    /// 最後の音素を音声の終わり `end` で閉じる
+    pub fn finish(&mut self, end: u64) -> Vec<Keyframe> {
+        let mut keyframes = self.close(end);
+        keyframes.extend(self.resolve(&self.set.silence()));
+        keyframes
+    }
+
+    fn close(&mut self, end: u64) -> Vec<Keyframe> {
+        match self.current.take() {
+            Some((start, Some(viseme))) => vec![Keyframe {
+                start,
+                end: end.max(start),
+                viseme,
+            }],
+            Some((start, None)) => {
+                self.waiting.push((start, end.max(start)));
+                vec![]
+            }
+            None => vec![],
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Vec<Keyframe> {
+        self.waiting
+            .drain(..)
+            .map(|(start, end)| Keyframe {
+                start,
+                end,
+                viseme: name.to_owned(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VisemeTrack {
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl VisemeTrack {
+    /// 同じ口の形が続くキーフレームはまとめる
+    pub fn from_timeline(timeline: &Timeline, set: &VisemeSet) -> Self {
+        let mut tracker = VisemeTracker::new(set.clone());
+        let mut keyframes: Vec<Keyframe> = vec![];
+
+        let phonemes = timeline
+            .track(Track::Phoneme)
+            .filter_map(|s| match &s.event {
+                SpeechEvent::Phoneme(name) => Some((s.start, name)),
+                _ => None,
+            });
+
+        let mut push = |new: Vec<Keyframe>| {
+            for keyframe in new {
+                match keyframes.last_mut() {
+                    Some(last) if last.viseme == keyframe.viseme && last.end == keyframe.start => {
+                        last.end = keyframe.end;
+                    }
+                    _ => keyframes.push(keyframe),
+                }
+            }
+        };
+
+        for (tick, phoneme) in phonemes {
+            push(tracker.push_phoneme(tick, phoneme));
+        }
+        push(tracker.finish(timeline.end()));
+
+        Self { keyframes }
+    }
+
+    /// `start_ms,end_ms,viseme`
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "start_ms,end_ms,viseme")?;
+
+        for keyframe in &self.keyframes {
+            let viseme = if keyframe.viseme.contains([',', '"', '\n']) {
+                format!("\"{}\"", keyframe.viseme.replace('"', "\"\""))
+            } else {
+                keyframe.viseme.clone()
+            };
+            writeln!(writer, "{},{},{viseme}", keyframe.start, keyframe.end)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut buffer = vec![];
+        self.write_csv(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json_string(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VisemeChunk {
+    Speech(SpeechChunk),
+    Keyframe(Keyframe),
+}
+
+/// `SpeechStream` などから受け取った音声・イベントに、確定したキーフレームを挟んで返す
+#[derive(Debug)]
+pub struct VisemeStream<I> {
+    inner: I,
+    tracker: VisemeTracker,
+    hz: u32,
+    /// ここまでに受け取ったサンプル数 (最後のキーフレームを閉じる tick を求める)
+    samples: usize,
+    pending: VecDeque<VisemeChunk>,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = SpeechChunk>> VisemeStream<I> {
+    /// `hz` は `Aitalked::hz_voice_db`
+    pub fn new(inner: I, set: VisemeSet, hz: u32) -> Self {
+        Self {
+            inner,
+            tracker: VisemeTracker::new(set),
+            hz,
+            samples: 0,
+            pending: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &I {
+        &self.inner
+    }
+}
+
+impl<I: Iterator<Item = SpeechChunk>> Iterator for VisemeStream<I> {
+    type Item = VisemeChunk;
+
+    fn next(&mut self) -> Option<VisemeChunk> {
+        if let Some(chunk) = self.pending.pop_front() {
+            return Some(chunk);
+        }
+
+        if self.finished {
+            return None;
+        }
+
+        let Some(chunk) = self.inner.next() else {
+            self.finished = true;
+            let end = timeline::sample_to_tick(self.hz, self.samples);
+            self.pending.extend(
+                self.tracker
+                    .finish(end)
+                    .into_iter()
+                    .map(VisemeChunk::Keyframe),
+            );
+            return self.pending.pop_front();
+        };
+
+        match &chunk {
+            SpeechChunk::Audio { samples, .. } => self.samples += samples.len(),
+            SpeechChunk::Event(event) => {
+                if let SpeechEvent::Phoneme(phoneme) = &event.event {
+                    self.pending.extend(
+                        self.tracker
+                            .push_phoneme(event.tick, phoneme)
+                            .into_iter()
+                            .map(VisemeChunk::Keyframe),
+                    );
+                }
+            }
+        }
+
+        self.pending.push_back(VisemeChunk::Speech(chunk));
+        self.pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::TimedEvent;
+
+    #[test]
+    fn stream_closes_last_keyframe_at_audio_end() {
+        let phoneme = |tick, name: &str| {
+            SpeechChunk::Event(TimedEvent {
+                tick,
+                event: SpeechEvent::Phoneme(name.to_owned()),
+            })
+        };
+        let chunks = vec![
+            phoneme(0, "a"),
+            SpeechChunk::Audio {
+                tick: 0,
+                samples: vec![0; 2205],
+            },
+            phoneme(100, "i"),
+            SpeechChunk::Audio {
+                tick: 100,
+                samples: vec![0; 2205],
+            },
+        ];
+
+        let keyframes: Vec<_> = VisemeStream::new(chunks.into_iter(), VisemeSet::japanese(), 22050)
+            .filter_map(|chunk| match chunk {
+                VisemeChunk::Keyframe(keyframe) => {
+                    Some((keyframe.viseme, keyframe.start..keyframe.end))
+                }
+                VisemeChunk::Speech(_) => None,
+            })
+            .collect();
+
+        assert_eq!(
+            keyframes,
+            [("あ".to_owned(), 0..100), ("い".to_owned(), 100..200)]
+        );
+    }
+}
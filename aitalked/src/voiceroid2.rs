@@ -0,0 +1,369 @@
+//! VOICEROID2 エディタのユーザー設定 (XML) に保存されたボイスプリセットの読み込み
+//!
+//! ```xml
+//! <VoicePreset>
+//!   <PresetName>琴葉 茜（ノーマル）</PresetName>
+//!   <VoiceName>akane_west_emo_44</VoiceName>
+//!   <Volume>1</Volume>
+//!   <Speed>1</Speed>
+//!   <Pitch>1</Pitch>
+//!   <PitchRange>1</PitchRange>
+//!   <MiddlePause>150</MiddlePause>
+//!   <LongPause>370</LongPause>
+//!   <Styles>
+//!     <Style Name="J" Value="0" />
+//!     <Style Name="A" Value="0" />
+//!     <Style Name="S" Value="0" />
+//!   </Styles>
+//! </VoicePreset>
+//! ```
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use roxmltree::{Document, Node};
+
+use crate::preset::{PresetLibrary, VoicePreset};
+use crate::style::{StyleRateError, StyleRates};
+
+/// エディタ上で省略された項目に使う既定値
+const DEFAULT_VOLUME: f32 = 1.0;
+const DEFAULT_SPEED: f32 = 1.0;
+const DEFAULT_PITCH: f32 = 1.0;
+const DEFAULT_RANGE: f32 = 1.0;
+const DEFAULT_PAUSE_MIDDLE: i32 = 150;
+const DEFAULT_PAUSE_LONG: i32 = 370;
+const DEFAULT_PAUSE_SENTENCE: i32 = 800;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EditorPreset {
+    pub name: String,
+    pub preset: VoicePreset,
+}
+
+/// 文書中の全ての `VoicePreset` 要素を読み込む
+pub fn parse_presets(xml: &str) -> Result<Vec<EditorPreset>, Voiceroid2Error> {
+    let document = Document::parse(xml)?;
+
+    document
+        .descendants()
+        .filter(|n| n.is_element() && n.tag_name().name() == "VoicePreset")
+        .map(parse_preset)
+        .collect()
+}
+
+pub fn load_presets(path: &Path) -> Result<Vec<EditorPreset>, Voiceroid2Error> {
+    let xml = std::fs::read_to_string(path)?;
+    parse_presets(&xml)
+}
+
+/// 同名のプリセットは後に出現したもので上書きされる
+pub fn load_library(path: &Path) -> Result<PresetLibrary, Voiceroid2Error> {
+    let mut library = PresetLibrary::new();
+
+    for preset in load_presets(path)? {
+        library.insert(preset.name, preset.preset);
+    }
+
+    Ok(library)
+}
+
+/// `dir` 以下 (サブディレクトリを含む) の設定ファイル候補
+///
+/// エディタが設定を保存する場所はバージョンやインストール方法によって異なるため、探す場所は呼び出し側で決める
+pub fn find_settings_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    collect_settings_files(dir, &mut files);
+    files
+}
+
+fn collect_settings_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if path.is_dir() {
+            collect_settings_files(&path, files);
+            continue;
+        }
+
+        if matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("settings" | "config" | "xml")
+        ) {
+            files.push(path);
+        }
+    }
+}
+
+fn parse_preset(node: Node) -> Result<EditorPreset, Voiceroid2Error> {
+    let voice_name = child_text(node, "VoiceName")
+        .ok_or(Voiceroid2Error::MissingElement("VoiceName"))?
+        .to_owned();
+
+    let name = child_text(node, "PresetName")
+        .map(str::to_owned)
+        .unwrap_or_else(|| voice_name.clone());
+
+    let mut styles = StyleRates::new();
+
+    if let Some(node) = child(node, "Styles") {
+        for style in node
+            .children()
+            .filter(|n| n.is_element() && n.tag_name().name() == "Style")
+        {
+            let style_name = style
+                .attribute("Name")
+                .ok_or(Voiceroid2Error::MissingAttribute("Name"))?;
+            let value = style
+                .attribute("Value")
+                .ok_or(Voiceroid2Error::MissingAttribute("Value"))?;
+
+            styles.set(style_name, parse_value("Style", value)?)?;
+        }
+    }
+
+    Ok(EditorPreset {
+        name,
+        preset: VoicePreset {
+            voice_name,
+            volume: child_value(node, "Volume")?.unwrap_or(DEFAULT_VOLUME),
+            speed: child_value(node, "Speed")?.unwrap_or(DEFAULT_SPEED),
+            pitch: child_value(node, "Pitch")?.unwrap_or(DEFAULT_PITCH),
+            range: child_value(node, "PitchRange")?.unwrap_or(DEFAULT_RANGE),
+            pause_middle: child_value(node, "MiddlePause")?.unwrap_or(DEFAULT_PAUSE_MIDDLE),
+            pause_long: child_value(node, "LongPause")?.unwrap_or(DEFAULT_PAUSE_LONG),
+            pause_sentence: child_value(node, "SentencePause")?.unwrap_or(DEFAULT_PAUSE_SENTENCE),
+            styles,
+        },
+    })
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|n| n.text()).map(str::trim)
+}
+
+fn child_value<T: FromStr>(node: Node, name: &'static str) -> Result<Option<T>, Voiceroid2Error> {
+    child_text(node, name)
+        .map(|value| parse_value(name, value))
+        .transpose()
+}
+
+fn parse_value<T: FromStr>(element: &'static str, value: &str) -> Result<T, Voiceroid2Error> {
+    // 整数値の項目が "150.0" のように保存されていることがあるため、f64経由でも読めるようにする
+    value
+        .parse()
+        .or_else(|_| {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.fract() == 0.0)
+                .and_then(|v| (v as i64).to_string().parse().ok())
+                .ok_or(())
+        })
+        .map_err(|_| Voiceroid2Error::InvalidValue {
+            element,
+            value: value.to_owned(),
+        })
+}
+
+#[derive(Debug)]
+pub enum Voiceroid2Error {
+    Io(std::io::Error),
+    Xml(roxmltree::Error),
+    MissingElement(&'static str),
+    MissingAttribute(&'static str),
+    InvalidValue {
+        element: &'static str,
+        value: String,
+    },
+    Style(StyleRateError),
+}
+
+impl fmt::Display for Voiceroid2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Xml(e) => write!(f, "{e}"),
+            Self::MissingElement(name) => write!(f, "<{name}> is missing in <VoicePreset>"),
+            Self::MissingAttribute(name) => write!(f, "{name} attribute is missing in <Style>"),
+            Self::InvalidValue { element, value } => {
+                write!(f, "invalid value in <{element}>: {value:?}")
+            }
+            Self::Style(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Voiceroid2Error {}
+
+impl From<std::io::Error> for Voiceroid2Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<roxmltree::Error> for Voiceroid2Error {
+    fn from(e: roxmltree::Error) -> Self {
+        Self::Xml(e)
+    }
+}
+
+impl From<StyleRateError> for Voiceroid2Error {
+    fn from(e: StyleRateError) -> Self {
+        Self::Style(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/voiceroid2")
+            .join(name)
+    }
+
+    fn read_fixture(name: &str) -> String {
+        std::fs::read_to_string(fixture(name)).unwrap()
+    }
+
+    #[test]
+    fn parses_every_preset_in_order() {
+        let presets = parse_presets(&read_fixture("user.settings")).unwrap();
+        let names: Vec<_> = presets.iter().map(|p| p.name.as_str()).collect();
+
+        assert_eq!(
+            names,
+            [
+                "琴葉 茜（ノーマル）",
+                "琴葉 葵（喜び）",
+                "紲星 あかり",
+                "琴葉 茜（ノーマル）"
+            ]
+        );
+        assert_eq!(presets[0].preset.voice_name, "akane_west_emo_44");
+        assert_eq!(presets[2].preset.voice_name, "akari_44");
+    }
+
+    #[test]
+    fn parses_values() {
+        let preset = &parse_presets(&read_fixture("user.settings")).unwrap()[1].preset;
+
+        assert_eq!(preset.volume, 1.25);
+        assert_eq!(preset.speed, 0.9);
+        assert_eq!(preset.pitch, 1.1);
+        assert_eq!(preset.range, 1.3);
+
+        // 空白は無視する
+        assert_eq!(
+            parse_presets(&read_fixture("user.settings")).unwrap()[2]
+                .preset
+                .volume,
+            0.8
+        );
+    }
+
+    #[test]
+    fn accepts_integers_written_as_floats() {
+        let preset = &parse_presets(&read_fixture("user.settings")).unwrap()[1].preset;
+
+        assert_eq!(preset.pause_middle, 120);
+        assert_eq!(preset.pause_long, 300);
+        assert_eq!(preset.pause_sentence, 650);
+    }
+
+    #[test]
+    fn rejects_fractional_integers() {
+        let xml = read_fixture("invalid_pause.settings");
+
+        assert!(matches!(
+            parse_presets(&xml),
+            Err(Voiceroid2Error::InvalidValue {
+                element: "MiddlePause",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn parses_styles() {
+        let presets = parse_presets(&read_fixture("user.settings")).unwrap();
+        let styles = &presets[1].preset.styles;
+
+        assert_eq!(styles.len(), 3);
+        assert_eq!(styles.get("J"), Some(0.8));
+        assert_eq!(styles.get("A"), Some(0.0));
+        assert_eq!(styles.get("S"), Some(0.15));
+        assert!(presets[2].preset.styles.is_empty());
+    }
+
+    #[test]
+    fn fills_missing_elements_with_defaults() {
+        let presets = parse_presets(&read_fixture("defaults.settings")).unwrap();
+        let preset = &presets[0];
+
+        assert_eq!(preset.name, "yukari_44");
+        assert_eq!(preset.preset.volume, DEFAULT_VOLUME);
+        assert_eq!(preset.preset.speed, DEFAULT_SPEED);
+        assert_eq!(preset.preset.pitch, DEFAULT_PITCH);
+        assert_eq!(preset.preset.range, DEFAULT_RANGE);
+        assert_eq!(preset.preset.pause_middle, DEFAULT_PAUSE_MIDDLE);
+        assert_eq!(preset.preset.pause_long, DEFAULT_PAUSE_LONG);
+        assert_eq!(preset.preset.pause_sentence, DEFAULT_PAUSE_SENTENCE);
+        assert!(preset.preset.styles.is_empty());
+
+        assert_eq!(presets[1].name, "結月 ゆかり（速め）");
+        assert_eq!(presets[1].preset.speed, 1.4);
+        assert_eq!(presets[1].preset.volume, DEFAULT_VOLUME);
+        assert!(presets[1].preset.styles.is_empty());
+    }
+
+    #[test]
+    fn requires_voice_name() {
+        let xml = read_fixture("missing_voice_name.settings");
+
+        assert!(matches!(
+            parse_presets(&xml),
+            Err(Voiceroid2Error::MissingElement("VoiceName"))
+        ));
+    }
+
+    #[test]
+    fn requires_style_value() {
+        let xml = read_fixture("missing_style_value.settings");
+
+        assert!(matches!(
+            parse_presets(&xml),
+            Err(Voiceroid2Error::MissingAttribute("Value"))
+        ));
+    }
+
+    #[test]
+    fn later_presets_override_earlier_ones_in_library() {
+        let library = load_library(&fixture("user.settings")).unwrap();
+        let presets = load_presets(&fixture("user.settings")).unwrap();
+
+        assert_eq!(library.presets.len(), 3);
+        assert_eq!(library.get("琴葉 茜（ノーマル）"), Some(&presets[3].preset));
+    }
+
+    #[test]
+    fn finds_settings_files_under_dir() {
+        let files = find_settings_files(&fixture(""));
+
+        assert_eq!(files.len(), 5);
+        assert!(files.contains(&fixture("user.settings")));
+        assert!(find_settings_files(&fixture("missing")).is_empty());
+    }
+}
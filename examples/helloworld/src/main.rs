@@ -1,8 +1,7 @@
 use std::ffi::{c_char, c_void, CStr, CString};
-use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 
-use aitalked::{api::Aitalked, binding::*, model::*};
+use aitalked::{api::Aitalked, audio::PcmBuffer, binding::*, model::*};
 use anyhow::Result;
 use clap::Parser;
 use directories::UserDirs;
@@ -197,9 +196,11 @@ async fn main() -> Result<()> {
         aitalked
     };
 
+    const HZ_VOICE_DB: u32 = 44100;
+
     let code = unsafe {
         aitalked.init(&AitalkedConfig {
-            hz_voice_db: 44100,
+            hz_voice_db: HZ_VOICE_DB,
             dir_voice_dbs: path_to_cstring(&args.installation_dir.join(&args.voice_dir)).as_ptr(),
             msec_timeout: 1000,
             path_license: path_to_cstring(&args.installation_dir.join(&args.aitalk_lic)).as_ptr(),
@@ -256,28 +257,12 @@ async fn main() -> Result<()> {
     /*\
     |*| Param Initialization
     \*/
-    let empty_tts_param_size = std::mem::size_of::<TtsParam>() as u32;
-    println!("Empty TtsParamSize: {empty_tts_param_size}");
-
-    let speaker_param_size = std::mem::size_of::<SpeakerParam>() as u32;
-    println!("SpeakerParamSize: {speaker_param_size}");
-
-    let mut actual_tts_param_size = 0;
-
-    let code = unsafe { aitalked.get_param(std::ptr::null_mut(), &mut actual_tts_param_size) };
-    println!("aitalked.get_param: {:?} (expects: INSUFFICIENT)", code);
-
-    println!("Actual TtsParamSize: {actual_tts_param_size}");
+    let speakers_len = unsafe { aitalked::layout::verify_engine(&aitalked) }?;
+    println!("Layout verified (speakers: {speakers_len})");
 
-    let estimate_speaker_param_count =
-        (actual_tts_param_size - empty_tts_param_size) / speaker_param_size;
-
-    println!("Estimate Speaker Param Count: {estimate_speaker_param_count}");
-
-    let mut boxed_tts_param = BoxedTtsParam::new(estimate_speaker_param_count as usize);
-    let code =
-        unsafe { aitalked.get_param(boxed_tts_param.tts_param_mut(), &mut actual_tts_param_size) };
-    println!("aitalked.get_param: {code:?}");
+    let mut boxed_tts_param = unsafe { aitalked.get_boxed_param() }?;
+    println!("Actual TtsParamSize: {}", boxed_tts_param.tts_param().size);
+    println!("Speaker Param Count: {}", boxed_tts_param.speakers_len());
 
     /*\
     |*| Set Params
@@ -296,9 +281,12 @@ async fn main() -> Result<()> {
     /*\
     |*| Start Text2Kana
     \*/
-    boxed_tts_param.tts_param_mut().proc_text_buf = Some(text_buffer_callback);
-    let code = unsafe { aitalked.set_param(boxed_tts_param.tts_param()) };
-    println!("aitalked.set_param: {code:?}");
+    let scope = unsafe {
+        aitalked.param_scope(&ParamOverrides {
+            proc_text_buf: Some(text_buffer_callback),
+            ..Default::default()
+        })
+    }?;
 
     let mut job_id = 0;
 
@@ -309,7 +297,7 @@ async fn main() -> Result<()> {
         aitalked,
         buffer: &mut buffer,
         notify: tx.clone(),
-        len_text_buf_bytes: boxed_tts_param.tts_param().len_text_buf_bytes,
+        len_text_buf_bytes: scope.tts_param().len_text_buf_bytes,
     };
 
     let code = unsafe {
@@ -331,9 +319,7 @@ async fn main() -> Result<()> {
     println!("aitalked.close_kana: {code:?}");
 
     // Unload proc_text_buf
-    boxed_tts_param.tts_param_mut().proc_text_buf = None;
-    let code = unsafe { aitalked.set_param(boxed_tts_param.tts_param()) };
-    println!("aitalked.set_param: {code:?}");
+    scope.restore()?;
 
     // Add '\0'
     buffer.push(0);
@@ -343,10 +329,13 @@ async fn main() -> Result<()> {
     /*\
     |*| Start Kana2Speech
     \*/
-    boxed_tts_param.tts_param_mut().proc_raw_buf = Some(raw_buf_callback);
-    boxed_tts_param.tts_param_mut().proc_event_tts = Some(tts_event_callback);
-    let code = unsafe { aitalked.set_param(boxed_tts_param.tts_param()) };
-    println!("aitalked.set_param: {code:?}");
+    let scope = unsafe {
+        aitalked.param_scope(&ParamOverrides {
+            proc_raw_buf: Some(raw_buf_callback),
+            proc_event_tts: Some(tts_event_callback),
+            ..Default::default()
+        })
+    }?;
 
     let mut job_id = 0;
     let (tx, mut rx) = mpsc::channel(1);
@@ -359,7 +348,7 @@ async fn main() -> Result<()> {
         events: &mut events,
         buffer: &mut buffer,
         notify: tx.clone(),
-        len_raw_buf_words: boxed_tts_param.tts_param().len_raw_buf_words,
+        len_raw_buf_words: scope.tts_param().len_raw_buf_words,
     };
 
     let code = unsafe {
@@ -382,28 +371,17 @@ async fn main() -> Result<()> {
         println!(" - {event:?}");
     }
 
-    // Unload
-    boxed_tts_param.tts_param_mut().proc_raw_buf = None;
-    boxed_tts_param.tts_param_mut().proc_event_tts = None;
-    let code = unsafe { aitalked.set_param(boxed_tts_param.tts_param()) };
-    println!("aitalked.set_param: {code:?}");
-
     let code = unsafe { aitalked.close_speech(job_id, 0) };
     println!("aitalked.close_speech: {code:?}");
 
+    // Unload proc_raw_buf, proc_event_tts
+    scope.restore()?;
+
     /*\
     |*| Write to WAVE file
     \*/
-    const WAV_HEADER_SIZE: usize = 44;
-    let mut file = BufWriter::new(std::fs::File::create("output.wav").unwrap());
-    file.write_all(b"RIFF")?;
-    file.write_all(&((buffer.len() + WAV_HEADER_SIZE) as u32).to_le_bytes())?;
-    file.write_all(b"WAVEfmt \x10\x00\x00\x00\x01\x00\x01\x00")?;
-    file.write_all(&44100u32.to_le_bytes())?;
-    file.write_all(&(44100u32 * 2).to_le_bytes())?;
-    file.write_all(b"\x02\x00\x10\x00data")?;
-    file.write_all(&(buffer.len() as u32).to_le_bytes())?;
-    file.write_all(&buffer)?;
+    let sample_rate = aitalked.hz_voice_db().unwrap_or(HZ_VOICE_DB);
+    PcmBuffer::from_le_bytes(sample_rate, &buffer).save_wav("output.wav")?;
 
     println!("Output file created");
 